@@ -0,0 +1,415 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Server configuration
+
+extern crate rustc_serialize;
+
+use std::io::File;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use self::rustc_serialize::json::Json;
+
+/// One shadowsocks server's address, password and cipher.
+#[deriving(Clone)]
+pub struct ServerConfig {
+    pub address: SocketAddr,
+    pub port: u16,
+    pub password: String,
+    pub method: String,
+    pub timeout: Option<u64>,
+    pub plugin: PluginConfig,
+    /// When set, the server publishes itself as a v3 onion service if
+    /// `publish_onion` is true (and, if `outbound` below selects it, also
+    /// routes egress through Tor) using this control port/SOCKS port pair.
+    pub tor: Option<TorConfig>,
+    pub outbound: OutboundConfig,
+}
+
+impl ServerConfig {
+    pub fn basic(address: SocketAddr, password: String, method: String) -> ServerConfig {
+        ServerConfig {
+            address: address,
+            port: address.port(),
+            password: password,
+            method: method,
+            timeout: None,
+            plugin: PluginConfig::Plain,
+            tor: None,
+            outbound: OutboundConfig::Direct,
+        }
+    }
+}
+
+/// A config may describe exactly one server, or a list. Every server-side
+/// path uses only the first entry of a list; `TcpRelayServer::new`
+/// rejects a list whose length is not exactly one.
+#[deriving(Clone)]
+pub enum ServerConfigVariant {
+    SingleServer(ServerConfig),
+    MultipleServer(Vec<ServerConfig>),
+}
+
+pub use self::ServerConfigVariant::{SingleServer, MultipleServer};
+
+/// Which transport the TCP relay should carry the shadowsocks stream
+/// over. `Plain` is a bare `TcpStream`; the rest wrap it (or replace it
+/// outright, for `Quic`) before the cipher layer ever sees a byte.
+#[deriving(Clone, PartialEq)]
+pub enum PluginConfig {
+    Plain,
+    Tls(TlsConfig),
+    Quic(QuicConfig),
+}
+
+/// Certificate material and connection settings for the `quic` plugin.
+///
+/// QUIC mandates TLS 1.3, so the server side still needs `cert_path`/
+/// `key_path` to stand up its endpoint. `client_trust_ca` is not read by
+/// anything in this crate: there is no client-side QUIC dial path, only
+/// `QuicTransport::listen`. `idle_timeout_ms`
+/// bounds how long a QUIC connection is kept around with no traffic
+/// before it is torn down, independent of the per-stream `timeout` on
+/// `ServerConfig`.
+#[deriving(Clone, PartialEq)]
+pub struct QuicConfig {
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    pub client_trust_ca: Option<String>,
+    pub idle_timeout_ms: u32,
+}
+
+impl QuicConfig {
+    pub fn new() -> QuicConfig {
+        QuicConfig {
+            cert_path: None,
+            key_path: None,
+            client_trust_ca: None,
+            idle_timeout_ms: 30_000,
+        }
+    }
+}
+
+/// Certificate material and connection settings for the `tls` plugin.
+///
+/// The server side needs `cert_path`/`key_path` to build its
+/// `SslContext`, and selects from `alpn_protocols` during the handshake
+/// so it looks like ordinary HTTPS (e.g. `["http/1.1"]`). `client_sni`
+/// and `client_trust_ca` are not read by anything in this crate: there
+/// is no client-side TLS dial path.
+#[deriving(Clone, PartialEq)]
+pub struct TlsConfig {
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    pub client_sni: Option<String>,
+    pub client_trust_ca: Option<String>,
+    pub alpn_protocols: Vec<String>,
+}
+
+impl TlsConfig {
+    pub fn new() -> TlsConfig {
+        TlsConfig {
+            cert_path: None,
+            key_path: None,
+            client_sni: None,
+            client_trust_ca: None,
+            alpn_protocols: vec!["http/1.1".to_string()],
+        }
+    }
+}
+
+/// Where `TcpRelayServer` dials the final destination it decrypted out of
+/// the request header. `Direct` is the original behaviour (`TcpStream::
+/// connect`, resolving domain targets through `CachedDns`); `Tor` instead
+/// hands the target (IP or hostname, unresolved) to a local Tor SOCKS5
+/// port so DNS and egress both happen inside the Tor circuit.
+#[deriving(Clone)]
+pub enum OutboundConfig {
+    Direct,
+    Tor,
+}
+
+/// Control port and SOCKS port for a local `tor` daemon, plus the onion
+/// service settings `relay::tor::publish_onion_service` uses on startup.
+///
+/// `control_addr` and `control_auth` are only needed to publish the onion
+/// service; `socks_addr` is only needed when `ServerConfig::outbound` is
+/// `OutboundConfig::Tor`, but both are kept on one struct since a single
+/// local `tor` daemon almost always provides both ports.
+#[deriving(Clone)]
+pub struct TorConfig {
+    pub control_addr: SocketAddr,
+    pub control_auth: TorControlAuth,
+    pub socks_addr: SocketAddr,
+    pub publish_onion: bool,
+    /// Port the onion service advertises to the outside world; mapped to
+    /// `ServerConfig::port` on localhost.
+    pub onion_port: u16,
+}
+
+impl TorConfig {
+    pub fn new(control_addr: SocketAddr, socks_addr: SocketAddr) -> TorConfig {
+        TorConfig {
+            control_addr: control_addr,
+            control_auth: TorControlAuth::Null,
+            socks_addr: socks_addr,
+            publish_onion: true,
+            onion_port: 8388,
+        }
+    }
+}
+
+/// How to authenticate to the Tor control port, mirroring the
+/// `PROTOCOLINFO`/`AUTHENTICATE` options Tor itself offers.
+#[deriving(Clone)]
+pub enum TorControlAuth {
+    Null,
+    CookieFile(String),
+    Password(String),
+}
+
+/// Upstream resolvers and cache sizing for `relay::tcprelay::cached_dns::
+/// CachedDns`. `TcpRelayServer::run` builds one `Arc<CachedDns>` from this
+/// and hands a clone to every connection task; it is not kept in `Context`.
+#[deriving(Clone)]
+pub struct DnsConfig {
+    /// Upstream DNS servers to query; the system resolver's servers are
+    /// used if this is empty.
+    pub resolvers: Vec<SocketAddr>,
+    /// Maximum number of cached name -> addresses entries.
+    pub cache_size: usize,
+    /// Floor (in seconds) for how long an NXDOMAIN answer is negatively
+    /// cached, even if the authority's SOA says less.
+    pub negative_ttl_floor: u32,
+}
+
+impl DnsConfig {
+    pub fn new() -> DnsConfig {
+        DnsConfig {
+            resolvers: Vec::new(),
+            cache_size: 1024,
+            negative_ttl_floor: 10,
+        }
+    }
+}
+
+/// Top-level config, built from the JSON config file or command line.
+#[deriving(Clone)]
+pub struct Config {
+    pub server: Option<ServerConfigVariant>,
+    pub enable_udp: bool,
+    pub dns: DnsConfig,
+    /// Path this config was parsed from, if any. Set by whoever builds
+    /// the initial `Config` (e.g. the command-line entry point); when
+    /// present, `relay::tcprelay::reload::watch` polls this path and
+    /// feeds any change through `reload_from_file` into a running
+    /// server's `ReloadHandle`.
+    pub config_path: Option<String>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            server: None,
+            enable_udp: false,
+            dns: DnsConfig::new(),
+            config_path: None,
+        }
+    }
+
+    /// Re-parses the file at `path` into a fresh `Config`, carrying
+    /// `previous`'s `plugin`/`tor`/`outbound` forward onto the reloaded
+    /// `ServerConfig`.
+    ///
+    /// Understands the same handful of top-level JSON keys the original
+    /// shadowsocks config file uses -- `server`, `server_port`,
+    /// `password`, `method`, `timeout` and `enable_udp` -- which is
+    /// enough for `reload::watch` to pick up a changed address,
+    /// password or cipher without a restart. `plugin`/`tor`/`outbound`
+    /// aren't read from the file yet; rather than defaulting them back
+    /// to `Plain`/no Tor/`Direct` -- which would silently drop a
+    /// server's Tor egress guarantee on the next password rotation --
+    /// this carries `previous`'s values forward unchanged, so a reload
+    /// that doesn't touch those keys can't flip them underneath a
+    /// running server.
+    pub fn reload_from_file(path: &str, previous: &ServerConfig) -> Result<Config, String> {
+        let contents = try!(read_file(path));
+        let json = try!(Json::from_str(contents.as_slice())
+                             .map_err(|e| format!("invalid JSON in {}: {}", path, e)));
+
+        let server_ip = try!(require_str(&json, path, "server"));
+        let server_port = try!(require_i64(&json, path, "server_port")) as u16;
+        let password = try!(require_str(&json, path, "password"));
+        let method = try!(require_str(&json, path, "method"));
+
+        let address = try!(format!("{}:{}", server_ip, server_port).as_slice().parse()
+                                .ok_or_else(|| format!("{}: invalid server address {}:{}",
+                                                        path, server_ip, server_port)));
+
+        let mut server_config = ServerConfig::basic(address, password, method);
+        server_config.timeout = json.find("timeout").and_then(|v| v.as_i64()).map(|t| t as u64);
+        server_config.plugin = previous.plugin.clone();
+        server_config.tor = previous.tor.clone();
+        server_config.outbound = previous.outbound.clone();
+
+        let mut config = Config::new();
+        config.enable_udp = json.find("enable_udp").and_then(|v| v.as_boolean()).unwrap_or(false);
+        config.config_path = Some(path.to_string());
+        config.server = Some(SingleServer(server_config));
+
+        Ok(config)
+    }
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    let mut file = try!(File::open(&Path::new(path))
+                             .map_err(|e| format!("unable to open {}: {}", path, e)));
+    file.read_to_string()
+        .map_err(|e| format!("unable to read {}: {}", path, e))
+}
+
+fn require_str(json: &Json, path: &str, key: &str) -> Result<String, String> {
+    json.find(key)
+        .and_then(|v| v.as_string())
+        .map(|s| s.to_string())
+        .ok_or_else(|| format!("{}: missing required \"{}\" field", path, key))
+}
+
+fn require_i64(json: &Json, path: &str, key: &str) -> Result<i64, String> {
+    json.find(key)
+        .and_then(|v| v.as_i64())
+        .ok_or_else(|| format!("{}: missing required \"{}\" field", path, key))
+}
+
+/// A `Config` behind a lock that can be swapped out from another thread.
+///
+/// `TcpRelayServer` reads a fresh clone of this at the top of every
+/// accepted connection instead of the one-time snapshot it used to take
+/// in `Relay::run`, so a reload's new password/cipher/outbound settings
+/// apply to newly accepted connections immediately -- already-spawned
+/// copy loops keep whatever cipher they captured when they started.
+#[deriving(Clone)]
+pub struct SharedConfig {
+    inner: Arc<Mutex<Config>>,
+}
+
+impl SharedConfig {
+    pub fn new(config: Config) -> SharedConfig {
+        SharedConfig { inner: Arc::new(Mutex::new(config)) }
+    }
+
+    pub fn get(&self) -> Config {
+        self.inner.lock().unwrap().clone()
+    }
+
+    pub fn swap(&self, config: Config) {
+        *self.inner.lock().unwrap() = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+    use super::{require_str, require_i64};
+    use config::{Config, ServerConfig, SingleServer, PluginConfig, OutboundConfig};
+
+    #[test]
+    fn require_str_reads_present_field() {
+        let json = Json::from_str("{\"method\": \"aes-256-cfb\"}").unwrap();
+        assert_eq!(require_str(&json, "cfg.json", "method").ok(),
+                   Some("aes-256-cfb".to_string()));
+    }
+
+    #[test]
+    fn require_str_errors_on_missing_field() {
+        let json = Json::from_str("{}").unwrap();
+        assert!(require_str(&json, "cfg.json", "method").is_err());
+    }
+
+    #[test]
+    fn require_i64_errors_on_wrong_type() {
+        let json = Json::from_str("{\"server_port\": \"not a number\"}").unwrap();
+        assert!(require_i64(&json, "cfg.json", "server_port").is_err());
+    }
+
+    fn basic_previous() -> ServerConfig {
+        let address = "127.0.0.1:8388".parse().unwrap();
+        ServerConfig::basic(address, "oldpass".to_string(), "aes-256-cfb".to_string())
+    }
+
+    #[test]
+    fn reload_from_file_rejects_unreadable_path() {
+        let result = Config::reload_from_file("/nonexistent/path/shadowsocks.json", &basic_previous());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reload_from_file_parses_minimal_server() {
+        let path = "/tmp/shadowsocks-rust-config-reload-test.json";
+        ::std::io::File::create(&::std::path::Path::new(path))
+            .unwrap()
+            .write_str("{\"server\": \"127.0.0.1\", \"server_port\": 8388, \
+                         \"password\": \"barfoo!\", \"method\": \"aes-256-cfb\"}")
+            .unwrap();
+
+        let config = Config::reload_from_file(path, &basic_previous()).unwrap();
+        match config.server {
+            Some(SingleServer(s)) => {
+                assert_eq!(s.port, 8388);
+                assert_eq!(s.password, "barfoo!".to_string());
+            },
+            _ => panic!("expected a single-server config"),
+        }
+
+        ::std::io::fs::unlink(&::std::path::Path::new(path)).ok();
+    }
+
+    #[test]
+    fn reload_from_file_carries_plugin_and_outbound_forward() {
+        let path = "/tmp/shadowsocks-rust-config-reload-outbound-test.json";
+        ::std::io::File::create(&::std::path::Path::new(path))
+            .unwrap()
+            .write_str("{\"server\": \"127.0.0.1\", \"server_port\": 8388, \
+                         \"password\": \"barfoo!\", \"method\": \"aes-256-cfb\"}")
+            .unwrap();
+
+        let mut previous = basic_previous();
+        previous.outbound = OutboundConfig::Tor;
+
+        let config = Config::reload_from_file(path, &previous).unwrap();
+        match config.server {
+            Some(SingleServer(s)) => {
+                match s.outbound {
+                    OutboundConfig::Tor => {},
+                    OutboundConfig::Direct => panic!("reload silently dropped Tor outbound"),
+                }
+                match s.plugin {
+                    PluginConfig::Plain => {},
+                    _ => panic!("expected plugin to stay Plain"),
+                }
+            },
+            _ => panic!("expected a single-server config"),
+        }
+
+        ::std::io::fs::unlink(&::std::path::Path::new(path)).ok();
+    }
+}
@@ -0,0 +1,291 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! AEAD ciphers (`chacha20-ietf-poly1305`, `aes-256-gcm`).
+//!
+//! Unlike the CFB stream ciphers, an AEAD cipher authenticates the data it
+//! encrypts, so the wire format has to change from "one continuous
+//! keystream" to a sequence of independently-authenticated chunks:
+//!
+//! ```text
+//! [salt][encrypted length][length tag][encrypted payload][payload tag]...
+//! ```
+//!
+//! The salt is the same length as the key and is sent once, in the clear,
+//! at the start of the connection. Both ends then run HKDF-SHA1 over the
+//! master key using that salt and the fixed info string `"ss-subkey"` to
+//! derive the session subkey. Every chunk after that is at most 0x3FFF
+//! bytes of payload, prefixed by its own 2-byte big-endian length -- both
+//! the length and the payload are encrypted and tagged separately, each
+//! using the next value of a 12-byte little-endian nonce counter that
+//! starts at zero and is incremented after every seal/open call.
+
+extern crate crypto;
+
+use self::crypto::hmac::Hmac;
+use self::crypto::sha1::Sha1;
+use self::crypto::hkdf::{hkdf_extract, hkdf_expand};
+use self::crypto::aead::{AeadEncryptor, AeadDecryptor};
+use self::crypto::chacha20poly1305::ChaCha20Poly1305;
+use self::crypto::aes_gcm::AesGcm;
+use self::crypto::aes::KeySize;
+
+use crypto::cipher::CipherType;
+
+/// Info string mixed into the HKDF expand step, fixed by the protocol.
+static SUBKEY_INFO: &'static [u8] = b"ss-subkey";
+
+/// Maximum size of a single chunk's payload.
+pub const MAX_CHUNK_SIZE: uint = 0x3FFF;
+
+/// Length in bytes of the Poly1305/GCM authentication tag.
+pub const TAG_SIZE: uint = 16;
+
+/// Length in bytes of the nonce used by both supported AEAD ciphers.
+const NONCE_SIZE: uint = 12;
+
+/// Derives the per-connection subkey from the master key and the
+/// connection's random salt, as specified above.
+pub fn derive_subkey(master_key: &[u8], salt: &[u8], key_len: uint) -> Vec<u8> {
+    let mut prk = Vec::from_elem(20u, 0u8); // SHA-1 output size
+    hkdf_extract::<Sha1>(salt, master_key, prk.as_mut_slice());
+
+    let mut subkey = Vec::from_elem(key_len, 0u8);
+    hkdf_expand::<Hmac<Sha1>>(prk.as_slice(), SUBKEY_INFO, subkey.as_mut_slice());
+    subkey
+}
+
+/// A little-endian nonce counter, incremented after every seal/open.
+struct NonceCounter {
+    bytes: [u8, .. NONCE_SIZE],
+}
+
+impl NonceCounter {
+    fn new() -> NonceCounter {
+        NonceCounter { bytes: [0u8, .. NONCE_SIZE] }
+    }
+
+    fn current(&self) -> [u8, .. NONCE_SIZE] {
+        self.bytes
+    }
+
+    fn increment(&mut self) {
+        for b in self.bytes.iter_mut() {
+            *b = b.wrapping_add(1);
+            if *b != 0 {
+                break
+            }
+        }
+    }
+}
+
+/// An AEAD cipher bound to one connection's derived subkey, tracking its
+/// own nonce counter. `CipherVariant::Aead` wraps one of these; the actual
+/// chunk framing (buffering partial reads into whole chunks) lives in
+/// `relay::tcprelay::aead_io`, which drives `seal`/`open` below one chunk
+/// at a time.
+pub struct AeadCipher {
+    cipher_type: CipherType,
+    salt: Vec<u8>,
+    subkey: Vec<u8>,
+    nonce: NonceCounter,
+}
+
+impl AeadCipher {
+    /// Generates a random salt and derives the session subkey from it.
+    /// The caller is responsible for writing `salt()` to the wire before
+    /// any sealed chunk.
+    pub fn new(t: CipherType, master_key: &[u8]) -> AeadCipher {
+        use std::rand::{Rng, OsRng};
+
+        let key_len = t.key_size();
+        let mut salt = Vec::from_elem(key_len, 0u8);
+        let mut rng = OsRng::new().ok().expect("failed to open OS random number generator");
+        rng.fill_bytes(salt.as_mut_slice());
+
+        AeadCipher::with_salt(t, master_key, salt.as_slice())
+    }
+
+    /// Derives the subkey from an already-known salt, e.g. one just read
+    /// off the wire from the peer.
+    pub fn with_salt(t: CipherType, master_key: &[u8], salt: &[u8]) -> AeadCipher {
+        AeadCipher {
+            cipher_type: t,
+            salt: salt.to_vec(),
+            subkey: derive_subkey(master_key, salt, t.key_size()),
+            nonce: NonceCounter::new(),
+        }
+    }
+
+    pub fn cipher_type(&self) -> CipherType {
+        self.cipher_type.clone()
+    }
+
+    /// The random salt this side generated (or received), sent in the
+    /// clear at the start of the connection.
+    pub fn salt(&self) -> &[u8] {
+        self.salt.as_slice()
+    }
+
+    /// Encrypts and tags `plain` (at most `MAX_CHUNK_SIZE` bytes), appending
+    /// the `TAG_SIZE`-byte tag, and advances the nonce.
+    pub fn seal(&mut self, plain: &[u8]) -> Vec<u8> {
+        assert!(plain.len() <= MAX_CHUNK_SIZE);
+        let nonce = self.nonce.current();
+        let sealed = aead_seal(self.cipher_type.clone(), self.subkey.as_slice(), &nonce, plain);
+        self.nonce.increment();
+        sealed
+    }
+
+    /// Verifies the tag on `sealed` and returns the plaintext, advancing
+    /// the nonce. Returns `None` if the tag does not match -- the caller
+    /// must treat that as a fatal, connection-ending error.
+    pub fn open(&mut self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let nonce = self.nonce.current();
+        let plain = aead_open(self.cipher_type.clone(), self.subkey.as_slice(), &nonce, sealed);
+        self.nonce.increment();
+        plain
+    }
+}
+
+impl Clone for AeadCipher {
+    fn clone(&self) -> AeadCipher {
+        AeadCipher {
+            cipher_type: self.cipher_type.clone(),
+            salt: self.salt.clone(),
+            subkey: self.subkey.clone(),
+            nonce: NonceCounter { bytes: self.nonce.bytes },
+        }
+    }
+}
+
+/// Dispatches to the underlying ChaCha20-Poly1305/AES-GCM primitive via the
+/// `AeadEncryptor`/`AeadDecryptor` traits `rust-crypto` actually exposes --
+/// each returns the ciphertext and tag separately through output
+/// parameters rather than as a single `Vec<u8>`, so `aead_seal`/`aead_open`
+/// below stitch the tag onto (or split it back off) the payload to give
+/// the rest of this module the one-`Vec<u8>`-per-chunk shape it wants.
+fn aead_seal(t: CipherType, key: &[u8], nonce: &[u8], plain: &[u8]) -> Vec<u8> {
+    let mut output = Vec::from_elem(plain.len(), 0u8);
+    let mut tag = Vec::from_elem(TAG_SIZE, 0u8);
+    match t {
+        CipherType::ChaCha20IetfPoly1305 => {
+            let mut cipher = ChaCha20Poly1305::new(key, nonce, &[]);
+            cipher.encrypt(plain, output.as_mut_slice(), tag.as_mut_slice());
+        },
+        CipherType::Aes256Gcm => {
+            let mut cipher = AesGcm::new(KeySize::KeySize256, key, nonce, &[]);
+            cipher.encrypt(plain, output.as_mut_slice(), tag.as_mut_slice());
+        },
+        _ => fail!("aead_seal called with a non-AEAD cipher type"),
+    }
+    output.push_all(tag.as_slice());
+    output
+}
+
+fn aead_open(t: CipherType, key: &[u8], nonce: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < TAG_SIZE {
+        return None;
+    }
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+    let mut output = Vec::from_elem(ciphertext.len(), 0u8);
+    let ok = match t {
+        CipherType::ChaCha20IetfPoly1305 => {
+            let mut cipher = ChaCha20Poly1305::new(key, nonce, &[]);
+            cipher.decrypt(ciphertext, output.as_mut_slice(), tag)
+        },
+        CipherType::Aes256Gcm => {
+            let mut cipher = AesGcm::new(KeySize::KeySize256, key, nonce, &[]);
+            cipher.decrypt(ciphertext, output.as_mut_slice(), tag)
+        },
+        _ => fail!("aead_open called with a non-AEAD cipher type"),
+    };
+    if ok { Some(output) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AeadCipher, derive_subkey};
+    use crypto::cipher::CipherType;
+
+    /// Independently computed (Python `hmac`/`hashlib`, not this module)
+    /// HKDF-SHA1 extract-then-expand over a fixed master key/salt with
+    /// the protocol's `"ss-subkey"` info string -- catches a wrong HKDF
+    /// wiring the way a same-process round trip against this module's
+    /// own `derive_subkey` never could.
+    #[test]
+    fn derive_subkey_matches_known_answer() {
+        let master_key: Vec<u8> = vec![
+            0x00u8, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+            0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+            0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17,
+            0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+        ];
+        let salt = Vec::from_elem(16u, 0xAAu8);
+        let expected = vec![
+            0xc0u8, 0x77, 0xf4, 0x54, 0x6c, 0xb5, 0x04, 0xbe,
+            0xd8, 0x83, 0x41, 0x24, 0x5e, 0x31, 0xa4, 0x9e,
+            0x04, 0xde, 0xbe, 0x9c, 0x93, 0xd9, 0xaf, 0x31,
+            0x4c, 0xc0, 0xab, 0xdd, 0xad, 0xc9, 0x83, 0xe2,
+        ];
+
+        let subkey = derive_subkey(master_key.as_slice(), salt.as_slice(), 32u);
+        assert_eq!(subkey, expected);
+    }
+
+    fn round_trip(t: CipherType) {
+        let key = Vec::from_elem(t.key_size(), 0x42u8);
+        let plain = b"a request header sealed as one single aead chunk payload".to_vec();
+
+        let mut sealer = AeadCipher::new(t.clone(), key.as_slice());
+        let sealed = sealer.seal(plain.as_slice());
+        assert!(sealed.len() > plain.len(), "sealed payload must carry the auth tag");
+
+        // The receiver derives its cipher from the salt the sealer
+        // generated, the same way `TcpRelayServer::handle_connection`
+        // reads the peer's salt off the wire before building its side.
+        let mut opener = AeadCipher::with_salt(t, key.as_slice(), sealer.salt());
+        assert_eq!(opener.open(sealed.as_slice()), Some(plain));
+    }
+
+    #[test]
+    fn chacha20_ietf_poly1305_round_trips() {
+        round_trip(CipherType::ChaCha20IetfPoly1305);
+    }
+
+    #[test]
+    fn aes_256_gcm_round_trips() {
+        round_trip(CipherType::Aes256Gcm);
+    }
+
+    #[test]
+    fn open_rejects_a_tampered_chunk() {
+        let t = CipherType::ChaCha20IetfPoly1305;
+        let key = Vec::from_elem(t.key_size(), 0x7u8);
+        let mut sealer = AeadCipher::new(t.clone(), key.as_slice());
+        let mut sealed = sealer.seal(b"hello");
+        let last = sealed.len() - 1;
+        sealed[last] ^= 0xff;
+
+        let mut opener = AeadCipher::with_salt(t, key.as_slice(), sealer.salt());
+        assert_eq!(opener.open(sealed.as_slice()), None);
+    }
+}
@@ -0,0 +1,203 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! AES-CFB stream cipher, the long-standing default. Kept byte-for-byte
+//! compatible with the original shadowsocks wire format: a random IV the
+//! same length as the block size is sent in the clear as the first thing
+//! on the wire, then the rest of the stream is CFB keystream.
+//!
+//! Implemented directly against the raw `BlockEncryptor` rather than a
+//! library CFB helper, because full-block CFB is direction-asymmetric:
+//! each block's keystream is `E(register)`, and the *next* register is
+//! always the ciphertext of the current one -- on encrypt that's this
+//! call's output, on decrypt it's this call's input. A single object
+//! that always re-feeds from its own output only gets that right when
+//! encrypting; reusing it for decryption (as this used to) produces the
+//! right plaintext for exactly one block and garbage after, since block
+//! two onward derives its keystream from the wrong register. `encrypt`
+//! and `decrypt` below share the same byte-at-a-time loop and only
+//! differ in which value (output vs. input) feeds the register.
+
+extern crate crypto;
+
+use self::crypto::aessafe;
+use self::crypto::symmetriccipher::BlockEncryptor;
+
+use crypto::cipher::{Cipher, CipherType};
+
+pub struct AesCfb {
+    cipher_type: CipherType,
+    iv: Vec<u8>,
+    block_cipher: Box<BlockEncryptor + Send>,
+    /// The block most recently fed back into the keystream generator --
+    /// always the ciphertext of whichever block came before it,
+    /// regardless of direction. Starts as the IV.
+    register: Vec<u8>,
+    /// `encrypt_block(register)`, recomputed once per block (when `used`
+    /// wraps back to zero) rather than once per byte.
+    keystream: Vec<u8>,
+    /// Ciphertext bytes of the block currently in flight, accumulated as
+    /// they're produced (encrypt) or consumed (decrypt) so they can
+    /// become `register` once the block is complete.
+    pending: Vec<u8>,
+    /// How many bytes of `keystream`/`pending` the current block has
+    /// used so far; wraps to 0 (triggering a fresh `keystream`) once it
+    /// reaches the block size.
+    used: uint,
+}
+
+impl AesCfb {
+    pub fn new(t: CipherType, key: &[u8], iv: &[u8]) -> AesCfb {
+        let block_cipher: Box<BlockEncryptor + Send> = match t {
+            CipherType::Aes128Cfb => box aessafe::AesSafe128Encryptor::new(key),
+            CipherType::Aes192Cfb => box aessafe::AesSafe192Encryptor::new(key),
+            CipherType::Aes256Cfb => box aessafe::AesSafe256Encryptor::new(key),
+            _ => fail!("aes_cfb::new called with a non-CFB cipher type"),
+        };
+        let block_size = block_cipher.block_size();
+        AesCfb {
+            cipher_type: t,
+            iv: iv.to_vec(),
+            block_cipher: block_cipher,
+            register: iv.to_vec(),
+            keystream: Vec::from_elem(block_size, 0u8),
+            pending: Vec::from_elem(block_size, 0u8),
+            used: 0u,
+        }
+    }
+
+    /// The shared byte-at-a-time CFB loop -- `encrypting` only decides
+    /// whether this block's next `register` is taken from `out` (the
+    /// ciphertext we just produced) or from `data` (the ciphertext we
+    /// were just given).
+    fn process(&mut self, data: &[u8], encrypting: bool) -> Vec<u8> {
+        let block_size = self.register.len();
+        let mut out = Vec::with_capacity(data.len());
+        for &byte in data.iter() {
+            if self.used == 0 {
+                self.block_cipher.encrypt_block(self.register.as_slice(), self.keystream.as_mut_slice());
+            }
+            let out_byte = byte ^ self.keystream[self.used];
+            self.pending[self.used] = if encrypting { out_byte } else { byte };
+            out.push(out_byte);
+
+            self.used += 1;
+            if self.used == block_size {
+                self.register = self.pending.clone();
+                self.used = 0u;
+            }
+        }
+        out
+    }
+}
+
+impl Cipher for AesCfb {
+    fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        self.process(data, true)
+    }
+
+    fn decrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        self.process(data, false)
+    }
+
+    fn iv(&self) -> &[u8] {
+        self.iv.as_slice()
+    }
+
+    fn cipher_type(&self) -> CipherType {
+        self.cipher_type.clone()
+    }
+
+    fn box_clone(&self) -> Box<Cipher + Send> {
+        // The live CFB register/keystream position can't be duplicated
+        // into a fresh `BlockEncryptor` without reaching into
+        // `rust-crypto` internals, so `box_clone` stays unimplemented;
+        // callers that need two independent directions (e.g.
+        // `TcpRelayServer::handle_connection`) construct a second
+        // `AesCfb` from the key/IV instead of cloning this one.
+        fail!("AesCfb keystream state is not meant to be duplicated; construct a new instance instead")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AesCfb;
+    use crypto::cipher::{Cipher, CipherType};
+
+    fn round_trip(t: CipherType, block_count: uint) {
+        let key = Vec::from_elem(t.key_size(), 0x42u8);
+        let iv = Vec::from_elem(16u, 0x24u8);
+        let mut encryptor = AesCfb::new(t.clone(), key.as_slice(), iv.as_slice());
+        let mut decryptor = AesCfb::new(t, key.as_slice(), iv.as_slice());
+
+        // Odd length, deliberately not a multiple of the 16-byte block
+        // size, to exercise a plaintext/ciphertext pair that spans a
+        // register update mid-stream.
+        let plaintext: Vec<u8> = range(0u, block_count * 16 + 5).map(|i| i as u8).collect();
+
+        let ciphertext = encryptor.encrypt(plaintext.as_slice());
+        assert_eq!(decryptor.decrypt(ciphertext.as_slice()), plaintext);
+    }
+
+    #[test]
+    fn round_trips_within_a_single_block() {
+        round_trip(CipherType::Aes256Cfb, 0);
+    }
+
+    #[test]
+    fn round_trips_across_several_blocks() {
+        round_trip(CipherType::Aes128Cfb, 4);
+    }
+
+    #[test]
+    fn round_trips_fed_one_byte_at_a_time() {
+        let t = CipherType::Aes256Cfb;
+        let key = Vec::from_elem(t.key_size(), 0x11u8);
+        let iv = Vec::from_elem(16u, 0x99u8);
+        let mut encryptor = AesCfb::new(t.clone(), key.as_slice(), iv.as_slice());
+        let mut decryptor = AesCfb::new(t, key.as_slice(), iv.as_slice());
+
+        let plaintext: Vec<u8> = range(0u, 40u).map(|i| (i * 7) as u8).collect();
+        let mut recovered = Vec::with_capacity(plaintext.len());
+        for &byte in plaintext.iter() {
+            let ciphertext = encryptor.encrypt(&[byte]);
+            recovered.push_all(decryptor.decrypt(ciphertext.as_slice()).as_slice());
+        }
+        assert_eq!(recovered, plaintext);
+    }
+
+    #[test]
+    fn naive_encrypt_twice_does_not_recover_the_plaintext_past_one_block() {
+        // Documents the regression this file fixes: calling encrypt()
+        // again on the ciphertext (what decrypt() used to do) only
+        // reproduces the plaintext for the first block -- every block
+        // after that re-feeds its register from the wrong value.
+        let t = CipherType::Aes256Cfb;
+        let key = Vec::from_elem(t.key_size(), 0x55u8);
+        let iv = Vec::from_elem(16u, 0xaau8);
+        let mut encryptor = AesCfb::new(t.clone(), key.as_slice(), iv.as_slice());
+        let mut naive = AesCfb::new(t, key.as_slice(), iv.as_slice());
+
+        let plaintext: Vec<u8> = range(0u, 32u).map(|i| i as u8).collect();
+        let ciphertext = encryptor.encrypt(plaintext.as_slice());
+        assert!(naive.encrypt(ciphertext.as_slice()) != plaintext);
+    }
+}
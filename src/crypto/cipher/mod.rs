@@ -0,0 +1,180 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Ciphers available to the relay: the original stream ciphers plus the
+//! newer AEAD family.
+
+use std::rand::{Rng, OsRng};
+
+use crypto::cipher::aead::AeadCipher;
+use crypto::cipher::aes_cfb::AesCfb;
+
+pub mod aead;
+mod aes_cfb;
+
+/// Types of ciphers. `with_name` constructs every type except `Table`,
+/// which no method name maps to.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum CipherType {
+    Table,
+    Aes128Cfb,
+    Aes192Cfb,
+    Aes256Cfb,
+    ChaCha20IetfPoly1305,
+    Aes256Gcm,
+}
+
+impl CipherType {
+    /// Length in bytes of the key (and therefore of the salt used by the
+    /// AEAD ciphers, which reuse the key length as the salt length).
+    pub fn key_size(&self) -> uint {
+        match *self {
+            Table => 0,
+            Aes128Cfb => 16,
+            Aes192Cfb => 24,
+            Aes256Cfb => 32,
+            ChaCha20IetfPoly1305 => 32,
+            Aes256Gcm => 32,
+        }
+    }
+
+    pub fn is_aead(&self) -> bool {
+        match *self {
+            ChaCha20IetfPoly1305 | Aes256Gcm => true,
+            _ => false,
+        }
+    }
+
+    /// Length in bytes of the IV a CFB stream cipher sends in the clear
+    /// before its keystream. AES is a 128-bit-block cipher regardless of
+    /// key length, so this is 16 for every CFB variant; AEAD ciphers use
+    /// `key_size()`-long salts instead and don't go through this.
+    pub fn iv_size(&self) -> uint {
+        match *self {
+            Table => 0,
+            Aes128Cfb | Aes192Cfb | Aes256Cfb => 16,
+            ChaCha20IetfPoly1305 | Aes256Gcm => 0,
+        }
+    }
+}
+
+/// Common interface shared by the stream ciphers. AEAD ciphers do not
+/// implement this trait directly -- they operate on whole chunks through
+/// `AeadCipher` instead, since a single `encrypt`/`decrypt` call on an
+/// arbitrary slice cannot carry a authentication tag per chunk.
+pub trait Cipher {
+    fn encrypt(&mut self, data: &[u8]) -> Vec<u8>;
+    fn decrypt(&mut self, data: &[u8]) -> Vec<u8>;
+
+    /// The IV this cipher was constructed with. The side that generated
+    /// it (rather than read it off the wire) has to write it in the
+    /// clear before the first encrypted chunk, the same way
+    /// `AeadCipher::salt` is written -- see `with_name` below.
+    fn iv(&self) -> &[u8];
+
+    /// The `CipherType` this cipher was constructed for, so
+    /// `CipherVariant::cipher_type` doesn't have to guess at it.
+    fn cipher_type(&self) -> CipherType;
+
+    /// Trait objects can't derive `Clone`, so `CipherVariant::clone`
+    /// delegates to this for `Stream`. `AesCfb` panics here instead of
+    /// duplicating its keystream state; `TcpRelayServer::handle_connection`
+    /// builds a separate cipher for each relay direction.
+    fn box_clone(&self) -> Box<Cipher + Send>;
+}
+
+/// Either one of the legacy stream ciphers, or an AEAD cipher that frames
+/// its payload into authenticated chunks.
+pub enum CipherVariant {
+    Stream(Box<Cipher + Send>),
+    Aead(AeadCipher),
+}
+
+impl CipherVariant {
+    pub fn cipher_type(&self) -> CipherType {
+        match *self {
+            Stream(ref c) => c.cipher_type(),
+            Aead(ref a) => a.cipher_type(),
+        }
+    }
+}
+
+impl Clone for CipherVariant {
+    fn clone(&self) -> CipherVariant {
+        match *self {
+            Stream(ref c) => Stream(c.box_clone()),
+            Aead(ref a) => Aead(a.clone()),
+        }
+    }
+}
+
+/// Constructs a `CipherVariant` from a method name and the user's password.
+///
+/// For the AEAD ciphers, `key` is the master key (the password, after the
+/// usual EVP_BytesToKey derivation) and the per-connection salt/subkey
+/// derivation happens inside `AeadCipher::new`. For the legacy stream
+/// ciphers, a fresh random IV is generated here and written by the caller
+/// as the first bytes of the connection.
+pub fn with_name(name: &str, key: &[u8]) -> Option<CipherVariant> {
+    let t = match type_from_name(name) {
+        Some(t) => t,
+        None => return None,
+    };
+
+    if t.is_aead() {
+        return Some(Aead(AeadCipher::new(t, key)));
+    }
+
+    let mut rng = OsRng::new().ok().expect("failed to open OS random number generator");
+    let mut iv = Vec::from_elem(t.iv_size(), 0u8);
+    rng.fill_bytes(iv.as_mut_slice());
+    Some(Stream(box AesCfb::new(t, key, iv.as_slice()) as Box<Cipher + Send>))
+}
+
+/// Builds the server-side cipher for a CFB method once the peer's IV has
+/// been read off the wire -- the stream-cipher counterpart of
+/// `with_salt` below. `with_name` can't be reused here because it always
+/// generates its own random IV rather than taking one.
+pub fn with_iv(t: CipherType, key: &[u8], iv: &[u8]) -> CipherVariant {
+    Stream(box AesCfb::new(t, key, iv) as Box<Cipher + Send>)
+}
+
+/// Maps a config method name to its `CipherType`, without constructing
+/// anything. The server side needs this on its own for AEAD methods: it
+/// has to read the peer's salt off the wire before it can build the
+/// cipher, so it can't go through `with_name`, which always generates its
+/// own salt.
+pub fn type_from_name(name: &str) -> Option<CipherType> {
+    match name {
+        "chacha20-ietf-poly1305" => Some(ChaCha20IetfPoly1305),
+        "aes-256-gcm" => Some(Aes256Gcm),
+        "aes-256-cfb" => Some(Aes256Cfb),
+        "aes-192-cfb" => Some(Aes192Cfb),
+        "aes-128-cfb" => Some(Aes128Cfb),
+        _ => None,
+    }
+}
+
+/// Builds the server-side cipher for an AEAD method once the client's
+/// salt has been read off the wire.
+pub fn with_salt(t: CipherType, key: &[u8], salt: &[u8]) -> CipherVariant {
+    Aead(AeadCipher::with_salt(t, key, salt))
+}
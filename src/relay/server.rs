@@ -8,7 +8,8 @@ use futures::Future;
 
 use relay::udprelay::server::run as run_udp;
 use relay::tcprelay::server::run as run_tcp;
-use config::Config;
+use relay::tor;
+use config::{Config, SingleServer, MultipleServer};
 use relay::Context;
 
 /// Relay server running on server side.
@@ -26,6 +27,12 @@ use relay::Context;
 /// run(config).unwrap();
 /// ```
 ///
+/// `ServerConfig::plugin` can disguise the relay as HTTPS (`Tls`) or swap
+/// the TCP listener for a QUIC endpoint (`Quic`); `tor`/`outbound` publish
+/// the server as a Tor onion service and/or route outbound connections to
+/// the target through Tor instead of dialing directly. The config can also
+/// be hot-reloaded from the same file on disk without dropping connections
+/// already in flight; see `Config::reload_from_file`.
 pub fn run(config: Config) -> io::Result<()> {
     let mut lp = try!(Core::new());
     let handle = lp.handle();
@@ -40,6 +47,21 @@ pub fn run(config: Config) -> io::Result<()> {
     //     lp.run(tcp_fut)
     // }
 
+    if let Some(ref variant) = config.server {
+        let server = match *variant {
+            SingleServer(ref s) => s.clone(),
+            MultipleServer(ref slist) => slist[0].clone(),
+        };
+        if let Some(ref tor_cfg) = server.tor {
+            if tor_cfg.publish_onion {
+                match tor::publish_onion_service(tor_cfg, server.port) {
+                    Ok(onion_addr) => info!("published onion service {}:{}", onion_addr, tor_cfg.onion_port),
+                    Err(err) => error!("failed to publish onion service: {}", err),
+                }
+            }
+        }
+    }
+
     let enable_udp = config.enable_udp;
     let context = Context::new(handle, config);
     Context::set(&context, move || if enable_udp {
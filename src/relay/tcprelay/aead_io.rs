@@ -0,0 +1,164 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Buffers a client-facing `LocalStream` into whole AEAD chunks.
+//!
+//! A single `read` on the underlying stream has no obligation to return a
+//! whole chunk (or even a whole 2-byte length prefix), so unlike the
+//! stateless `Cipher::encrypt`/`decrypt` used by the CFB ciphers, the AEAD
+//! side needs to hang on to a partially-read chunk across calls. That's
+//! what `AeadReader`/`AeadWriter` are for; `TcpRelayServer` reaches for
+//! them instead of raw `cipher.encrypt`/`decrypt` whenever the configured
+//! cipher turns out to be a `CipherVariant::Aead`.
+
+use std::io::IoResult;
+
+use crypto::cipher::aead::{AeadCipher, MAX_CHUNK_SIZE, TAG_SIZE};
+use relay::tcprelay::local_stream::LocalStream;
+
+/// Reads whole AEAD chunks off a `LocalStream`, opening each one as it
+/// completes and handing back the plaintext payload.
+pub struct AeadReader {
+    cipher: AeadCipher,
+}
+
+impl AeadReader {
+    pub fn new(cipher: AeadCipher) -> AeadReader {
+        AeadReader { cipher: cipher }
+    }
+
+    /// Hands back the underlying cipher so its nonce counter can keep
+    /// advancing across calls that build a fresh `AeadReader` -- e.g. the
+    /// request header is read before the copy loop starts, but both have
+    /// to share one continuous nonce sequence.
+    pub fn into_cipher(self) -> AeadCipher {
+        self.cipher
+    }
+
+    fn read_exact(&self, stream: &mut LocalStream, len: uint) -> IoResult<Vec<u8>> {
+        let mut buf = Vec::from_elem(len, 0u8);
+        try!(stream.read_at_least(len, buf.as_mut_slice()));
+        Ok(buf)
+    }
+
+    /// Blocks until one whole chunk (length prefix + payload, each sealed
+    /// separately) has arrived, then returns its decrypted payload.
+    pub fn read_chunk(&mut self, stream: &mut LocalStream) -> IoResult<Vec<u8>> {
+        let sealed_len = try!(self.read_exact(stream, 2 + TAG_SIZE));
+        let len_buf = match self.cipher.open(sealed_len.as_slice()) {
+            Some(buf) => buf,
+            None => return Err(bad_tag()),
+        };
+        let payload_len = try!(decode_chunk_len(len_buf.as_slice()));
+
+        let sealed_payload = try!(self.read_exact(stream, payload_len + TAG_SIZE));
+        match self.cipher.open(sealed_payload.as_slice()) {
+            Some(buf) => Ok(buf),
+            None => Err(bad_tag()),
+        }
+    }
+}
+
+/// Seals whole chunks (splitting payloads larger than `MAX_CHUNK_SIZE`)
+/// before they go out on a `LocalStream`.
+pub struct AeadWriter {
+    cipher: AeadCipher,
+}
+
+impl AeadWriter {
+    pub fn new(cipher: AeadCipher) -> AeadWriter {
+        AeadWriter { cipher: cipher }
+    }
+
+    pub fn into_cipher(self) -> AeadCipher {
+        self.cipher
+    }
+
+    /// Splits `data` into `MAX_CHUNK_SIZE`-sized pieces, seals the length
+    /// prefix and the payload of each one separately, and writes the
+    /// result to `stream`.
+    pub fn write_chunks(&mut self, stream: &mut LocalStream, data: &[u8]) -> IoResult<()> {
+        let mut offset = 0u;
+        while offset < data.len() {
+            let end = ::std::cmp::min(offset + MAX_CHUNK_SIZE, data.len());
+            let chunk = data.slice(offset, end);
+
+            let len_buf = [(chunk.len() >> 8) as u8, (chunk.len() & 0xff) as u8];
+            let sealed_len = self.cipher.seal(&len_buf);
+            try!(stream.write(sealed_len.as_slice()));
+
+            let sealed_payload = self.cipher.seal(chunk);
+            try!(stream.write(sealed_payload.as_slice()));
+
+            offset = end;
+        }
+        Ok(())
+    }
+}
+
+fn bad_tag() -> ::std::io::IoError {
+    ::std::io::IoError {
+        kind: ::std::io::OtherIoError,
+        desc: "AEAD tag verification failed",
+        detail: None,
+    }
+}
+
+/// A correctly-sealed length prefix can never decrypt to a value over
+/// `MAX_CHUNK_SIZE` -- both ends of a genuine shadowsocks AEAD stream
+/// always mask it to 14 bits before sealing. Seeing a larger value means
+/// the stream is corrupted or tampered with, so this is treated the same
+/// as a failed tag rather than silently masked into a smaller length,
+/// which would desync chunk boundaries for the rest of the connection.
+fn decode_chunk_len(len_buf: &[u8]) -> ::std::io::IoResult<uint> {
+    let payload_len = (len_buf[0] as uint) << 8 | (len_buf[1] as uint);
+    if payload_len > MAX_CHUNK_SIZE {
+        return Err(bad_chunk_len());
+    }
+    Ok(payload_len)
+}
+
+fn bad_chunk_len() -> ::std::io::IoError {
+    ::std::io::IoError {
+        kind: ::std::io::OtherIoError,
+        desc: "AEAD chunk length prefix exceeds MAX_CHUNK_SIZE",
+        detail: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_chunk_len;
+    use crypto::cipher::aead::MAX_CHUNK_SIZE;
+
+    #[test]
+    fn decode_chunk_len_accepts_max_size() {
+        let len_buf = [(MAX_CHUNK_SIZE >> 8) as u8, (MAX_CHUNK_SIZE & 0xff) as u8];
+        assert_eq!(decode_chunk_len(len_buf.as_slice()).ok(), Some(MAX_CHUNK_SIZE));
+    }
+
+    #[test]
+    fn decode_chunk_len_rejects_oversized_length() {
+        let bogus_len = MAX_CHUNK_SIZE + 1;
+        let len_buf = [(bogus_len >> 8) as u8, (bogus_len & 0xff) as u8];
+        assert!(decode_chunk_len(len_buf.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! TTL-aware, negative-caching DNS resolution for `TcpRelayServer`'s
+//! `DomainNameAddress` connect path.
+//!
+//! `trust-dns-resolver`'s synchronous `Resolver` does the actual lookups
+//! and already maintains its own TTL-respecting, NXDOMAIN-negative-caching
+//! cache, so `CachedDns` only owns building it from `DnsConfig` and
+//! handing every cached A/AAAA answer back as a string the old blocking
+//! `TcpStream::connect(host, port)` can take directly -- in the order the
+//! connect path should try them in, so a dead first address fails over to
+//! the next instead of giving up on the whole connection.
+
+use config::DnsConfig;
+
+use trust_dns_resolver::Resolver;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts, NameServerConfig, Protocol};
+
+pub struct CachedDns {
+    resolver: Resolver,
+}
+
+impl CachedDns {
+    /// Builds a resolver querying `conf.resolvers` (or the system's
+    /// configured servers, if empty), sized and floored per `conf`.
+    pub fn new(conf: &DnsConfig) -> CachedDns {
+        let resolver_config = if conf.resolvers.is_empty() {
+            ResolverConfig::default()
+        } else {
+            let mut rc = ResolverConfig::new();
+            for addr in conf.resolvers.iter() {
+                rc.add_name_server(NameServerConfig {
+                    socket_addr: *addr,
+                    protocol: Protocol::Udp,
+                    tls_dns_name: None,
+                });
+            }
+            rc
+        };
+
+        let mut opts = ResolverOpts::default();
+        opts.cache_size = conf.cache_size;
+        opts.negative_min_ttl = Some(::std::time::Duration::from_secs(conf.negative_ttl_floor as u64));
+
+        CachedDns {
+            resolver: Resolver::new(resolver_config, opts)
+                              .ok().expect("Unable to build dns resolver"),
+        }
+    }
+
+    /// Every A/AAAA address currently cached (or just looked up) for
+    /// `name`, in the order the connect path should try them. `None`
+    /// means the name doesn't resolve at all -- including a cached
+    /// NXDOMAIN that hasn't expired yet -- same as the old single-address
+    /// `resolve` returned for a failed lookup.
+    ///
+    /// The lookup is synchronous: this calls `trust-dns-resolver`'s
+    /// blocking `lookup_ip`, not its `AsyncResolver`, and blocks the
+    /// calling thread until the answer (or error) arrives. Every caller is
+    /// a `TcpRelayServer::handle_connection` running on its own thread,
+    /// spawned per accepted connection, so a slow lookup blocks only that
+    /// one connection, never the accept loop or any other connection.
+    /// `resolve` takes `&self` and `CachedDns` is shared as a plain
+    /// `Arc<CachedDns>`, so no lock is held across the blocking call.
+    pub fn resolve(&self, name: &str) -> Option<Vec<String>> {
+        match self.resolver.lookup_ip(name) {
+            Ok(lookup) => {
+                let addrs: Vec<String> = lookup.iter().map(|ip| ip.to_string()).collect();
+                if addrs.is_empty() { None } else { Some(addrs) }
+            },
+            Err(..) => None,
+        }
+    }
+}
@@ -0,0 +1,362 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! One local-side stream type for `TcpRelayServer::run`'s accept loop to
+//! hand to the cipher layer, whichever `plugin` produced it.
+//!
+//! `Plain` is a bare accepted `TcpStream`; `Tls` wraps one in a blocking
+//! server-side TLS handshake via the `openssl` crate (the async
+//! `TlsTransport` that used to live in `relay::transport` can't be
+//! driven here, since nothing on this thread runs a `tokio_core`
+//! reactor), behind the one lock described on `TlsShared`; `Quic`
+//! instead holds a handle onto a `QuicStream` accepted on
+//! `relay::tcprelay::quic_bridge`'s dedicated reactor thread, split into
+//! genuinely independent send/recv halves. All three implement the same
+//! old `Reader`/`Writer` pair `handle_connect_local`/
+//! `handle_connect_remote` already expect, so none of that code needs to
+//! know which transport it's moving bytes over.
+
+use std::io::{IoResult, IoError, OtherIoError, TimedOut, TcpStream, Reader, Writer};
+use std::sync::{Arc, Mutex};
+
+use openssl::ssl::{SslContext, SslMethod, SslStream};
+use openssl::x509::X509FileType;
+
+use config::TlsConfig;
+use relay::transport::{QuicSendHalf, QuicRecvHalf};
+
+pub enum LocalStream {
+    Plain(TcpStream),
+    /// `TlsSendHalf`/`TlsRecvHalf` each wrap the one `TlsShared` a
+    /// connection has -- see its doc comment for why this isn't the
+    /// genuinely-independent split `Quic` gets below.
+    Tls(Arc<Mutex<TlsSendHalf>>, Arc<Mutex<TlsRecvHalf>>),
+    /// Send and receive halves behind their own locks (rather than one
+    /// `Mutex<QuicStream>` for both) so a slow write spinning on
+    /// `WouldBlock` can't stall the read side's lock acquisition, and
+    /// vice versa -- see `QuicStream::split`.
+    Quic(Arc<Mutex<QuicSendHalf>>, Arc<Mutex<QuicRecvHalf>>),
+}
+
+/// How long a single lock acquisition is allowed to block on I/O before
+/// giving the other direction a turn -- see `TlsShared`'s doc comment.
+const TLS_POLL_INTERVAL_MS: u64 = 50;
+
+/// The one `SslStream` a TLS connection has, behind a single `Mutex`.
+///
+/// Unlike `Quic`'s `quinn::SendStream`/`RecvStream`, an `SslStream` isn't
+/// something the `openssl` crate documents as safe to read from one
+/// thread while writing from another -- session ticket handling, alert
+/// delivery and internal buffered-I/O state aren't guaranteed to be
+/// neatly partitioned by direction, which is exactly why `SslStream`
+/// itself isn't `Sync`. So all access here is serialized behind one real
+/// lock rather than asserting otherwise.
+///
+/// What still needs fixing is the deadlock a single lock invites: a
+/// request/response protocol has the local->remote thread blocked in
+/// `read`, waiting on bytes the client won't send until it sees the
+/// response, while remote->local blocks on the very same lock trying to
+/// `write` that response. The fix is to never hold the lock across an
+/// indefinite blocking call: the underlying socket is pinned to a short
+/// `TLS_POLL_INTERVAL_MS` timeout (regardless of whatever idle timeout
+/// `LocalStream::set_timeout` configured, which is tracked here
+/// separately and enforced in software), so `with_stream` only ever
+/// holds the lock for one short poll at a time, releasing it in between
+/// for the other direction to get in.
+struct TlsShared {
+    stream: Mutex<SslStream<TcpStream>>,
+    idle_timeout_ms: Mutex<Option<u64>>,
+}
+
+impl TlsShared {
+    fn new(mut stream: SslStream<TcpStream>) -> TlsShared {
+        stream.get_mut().set_timeout(Some(TLS_POLL_INTERVAL_MS));
+        TlsShared { stream: Mutex::new(stream), idle_timeout_ms: Mutex::new(None) }
+    }
+
+    fn set_idle_timeout(&self, timeout_ms: Option<u64>) {
+        *self.idle_timeout_ms.lock().unwrap() = timeout_ms;
+    }
+
+    /// Runs `op` against the shared stream one short poll at a time,
+    /// releasing the lock between attempts instead of holding it across
+    /// a blocking wait -- see the struct doc comment. A `TimedOut` from
+    /// the socket's `TLS_POLL_INTERVAL_MS` timeout just means nothing
+    /// was ready yet and triggers a retry; once `waited_ms` reaches the
+    /// real idle timeout configured via `set_idle_timeout` (if any),
+    /// that's surfaced to the caller as a genuine `TimedOut` instead.
+    fn with_stream<T, F>(&self, mut op: F) -> IoResult<T>
+            where F: FnMut(&mut SslStream<TcpStream>) -> IoResult<T> {
+        let idle_timeout_ms = *self.idle_timeout_ms.lock().unwrap();
+        let mut waited_ms = 0u64;
+        loop {
+            match op(&mut *self.stream.lock().unwrap()) {
+                Err(IoError { kind: TimedOut, .. }) => {
+                    waited_ms += TLS_POLL_INTERVAL_MS;
+                    if let Some(limit) = idle_timeout_ms {
+                        if waited_ms >= limit {
+                            return Err(IoError { kind: TimedOut,
+                                                  desc: "tls stream idle timeout",
+                                                  detail: None });
+                        }
+                    }
+                },
+                other => return other,
+            }
+        }
+    }
+
+    fn close_read(&self) -> IoResult<()> {
+        self.stream.lock().unwrap().get_mut().close_read()
+    }
+
+    fn close_write(&self) -> IoResult<()> {
+        self.stream.lock().unwrap().get_mut().close_write()
+    }
+}
+
+/// The write half of a split `LocalStream::Tls`. See `TlsShared`.
+pub struct TlsSendHalf {
+    shared: Arc<TlsShared>,
+}
+
+/// The read half of a split `LocalStream::Tls`. See `TlsShared`.
+pub struct TlsRecvHalf {
+    shared: Arc<TlsShared>,
+}
+
+impl LocalStream {
+    pub fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        match *self {
+            LocalStream::Plain(ref mut s) => s.set_timeout(timeout_ms),
+            // The real idle timeout, enforced in software across
+            // `with_stream`'s short polls -- see `TlsShared`. Either
+            // half reaches the same shared state.
+            LocalStream::Tls(_, ref recv) => recv.lock().unwrap().shared.set_idle_timeout(timeout_ms),
+            LocalStream::Quic(..) => {
+                // No TCP-style per-socket timeout to set here -- an idle
+                // QUIC connection is instead bounded by
+                // `QuicConfig::idle_timeout_ms` on the endpoint itself.
+            },
+        }
+    }
+
+    pub fn close_read(&mut self) -> IoResult<()> {
+        match *self {
+            LocalStream::Plain(ref mut s) => s.close_read(),
+            LocalStream::Tls(_, ref recv) => recv.lock().unwrap().shared.close_read(),
+            LocalStream::Quic(..) => Ok(()),
+        }
+    }
+
+    pub fn close_write(&mut self) -> IoResult<()> {
+        match *self {
+            LocalStream::Plain(ref mut s) => s.close_write(),
+            LocalStream::Tls(ref send, _) => send.lock().unwrap().shared.close_write(),
+            LocalStream::Quic(..) => Ok(()),
+        }
+    }
+
+    /// A second handle onto the same connection, the way `TcpStream::
+    /// clone` already gives `Relay::run` one handle per copy-loop
+    /// direction. `Tls` and `Quic` hand back `Arc` clones rather than a
+    /// true duplicated socket, since neither an `SslStream` nor a `quinn`
+    /// stream has a `dup`-like primitive; `Quic`'s two directions each
+    /// touch only their own half's lock so they never block on each
+    /// other, but `Tls`'s two halves still share one lock underneath --
+    /// see `TlsShared`'s doc comment for how that avoids deadlocking
+    /// without asserting anything unsafe about `SslStream`.
+    pub fn duplicate(&self) -> LocalStream {
+        match *self {
+            LocalStream::Plain(ref s) => LocalStream::Plain(s.clone()),
+            LocalStream::Tls(ref send, ref recv) => LocalStream::Tls(send.clone(), recv.clone()),
+            LocalStream::Quic(ref send, ref recv) => LocalStream::Quic(send.clone(), recv.clone()),
+        }
+    }
+}
+
+impl Reader for LocalStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        match *self {
+            LocalStream::Plain(ref mut s) => s.read(buf),
+            LocalStream::Tls(_, ref recv) => recv.lock().unwrap().shared.with_stream(|s| s.read(buf)),
+            LocalStream::Quic(_, ref recv) => {
+                match recv.lock().unwrap().read_blocking(buf) {
+                    Ok(n) => Ok(n),
+                    Err(e) => Err(quic_error(e.to_string())),
+                }
+            },
+        }
+    }
+}
+
+impl Writer for LocalStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        match *self {
+            LocalStream::Plain(ref mut s) => s.write(buf),
+            LocalStream::Tls(ref send, _) => send.lock().unwrap().shared.with_stream(|s| s.write(buf)),
+            LocalStream::Quic(ref send, _) => {
+                let mut guard = send.lock().unwrap();
+                let mut offset = 0u;
+                while offset < buf.len() {
+                    match guard.write_blocking(buf.slice_from(offset)) {
+                        Ok(n) => offset += n,
+                        Err(e) => return Err(quic_error(e.to_string())),
+                    }
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+fn quic_error(detail: String) -> IoError {
+    IoError { kind: OtherIoError, desc: "QUIC stream I/O error", detail: Some(detail) }
+}
+
+/// Encodes `protocols` into the wire format ALPN actually negotiates on:
+/// one length-prefixed name after another (e.g. `["http/1.1"]` becomes
+/// `[8, b'h', b't', b't', b'p', b'/', b'1', b'.', b'1']`).
+///
+/// Only the test suite below builds wire-format ALPN offers with this --
+/// there is no client-side dial path in this crate to exercise it in
+/// production. Outside the tests, `select_alpn_protocol`'s only caller is
+/// the server-side ALPN callback `build_ssl_context` registers, which
+/// receives the client's offer from OpenSSL in this wire format and
+/// decodes it with `decode_alpn_wire_format` rather than building one.
+#[cfg(test)]
+fn alpn_wire_format(protocols: &[String]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for p in protocols.iter() {
+        buf.push(p.len() as u8);
+        buf.push_all(p.as_bytes());
+    }
+    buf
+}
+
+/// Loads `cfg`'s certificate/key once per listener bind (not once per
+/// connection -- re-parsing them from disk on every accept would be
+/// wasteful) so `accept_tls` just has to run the handshake.
+///
+/// Also registers an ALPN selection callback so the handshake actually
+/// negotiates one of `cfg.alpn_protocols` (rather than just carrying the
+/// field around unused) -- picking the first name the connecting client
+/// offered that's also in our list, same as a real HTTPS server would.
+pub fn build_ssl_context(cfg: &TlsConfig) -> SslContext {
+    let cert_path = cfg.cert_path.as_ref()
+        .expect("tls plugin enabled on the server without cert_path configured");
+    let key_path = cfg.key_path.as_ref()
+        .expect("tls plugin enabled on the server without key_path configured");
+
+    let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+    ctx.set_certificate_file(cert_path.as_slice(), X509FileType::PEM).unwrap();
+    ctx.set_private_key_file(key_path.as_slice(), X509FileType::PEM).unwrap();
+
+    let our_protos = cfg.alpn_protocols.clone();
+    ctx.set_alpn_select_callback(move |client_protos: &[u8]| {
+        select_alpn_protocol(our_protos.as_slice(), client_protos)
+    });
+
+    ctx
+}
+
+/// Picks the first of `our_protos` that also appears in the client's
+/// ALPN offer, matching how a browser negotiating HTTPS would see only
+/// one of its offered protocols come back selected. Falls back to
+/// rejecting the handshake (`None`) if nothing overlaps.
+fn select_alpn_protocol(our_protos: &[String], client_wire_protos: &[u8]) -> Option<Vec<u8>> {
+    let offered = decode_alpn_wire_format(client_wire_protos);
+    our_protos.iter()
+              .find(|ours| offered.iter().any(|theirs| theirs == ours.as_bytes()))
+              .map(|ours| ours.as_bytes().to_vec())
+}
+
+fn decode_alpn_wire_format(wire: &[u8]) -> Vec<&[u8]> {
+    let mut protos = Vec::new();
+    let mut offset = 0u;
+    while offset < wire.len() {
+        let len = wire[offset] as uint;
+        offset += 1;
+        if offset + len > wire.len() {
+            break
+        }
+        protos.push(wire.slice(offset, offset + len));
+        offset += len;
+    }
+    protos
+}
+
+/// Runs a blocking server-side TLS handshake over a freshly-accepted
+/// `TcpStream` -- the disguise-as-HTTPS half of the `tls` plugin.
+pub fn accept_tls(stream: TcpStream, ctx: &SslContext) -> IoResult<LocalStream> {
+    match SslStream::accept(ctx, stream) {
+        Ok(ssl_stream) => {
+            let shared = Arc::new(TlsShared::new(ssl_stream));
+            let send = Arc::new(Mutex::new(TlsSendHalf { shared: shared.clone() }));
+            let recv = Arc::new(Mutex::new(TlsRecvHalf { shared: shared }));
+            Ok(LocalStream::Tls(send, recv))
+        },
+        Err(err) => Err(IoError { kind: OtherIoError, desc: "TLS handshake failed", detail: Some(err.to_string()) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{alpn_wire_format, decode_alpn_wire_format, select_alpn_protocol};
+
+    #[test]
+    fn alpn_wire_format_length_prefixes_each_name() {
+        let protos = vec!["http/1.1".to_string(), "h2".to_string()];
+        let wire = alpn_wire_format(protos.as_slice());
+        assert_eq!(wire, vec![8u8, b'h', b't', b't', b'p', b'/', b'1', b'.', b'1', 2u8, b'h', b'2']);
+    }
+
+    #[test]
+    fn decode_alpn_wire_format_round_trips_through_encode() {
+        let protos = vec!["http/1.1".to_string(), "h2".to_string()];
+        let wire = alpn_wire_format(protos.as_slice());
+        let decoded = decode_alpn_wire_format(wire.as_slice());
+        assert_eq!(decoded, vec!["http/1.1".as_bytes(), "h2".as_bytes()]);
+    }
+
+    #[test]
+    fn decode_alpn_wire_format_stops_at_a_truncated_entry() {
+        // A length byte claiming more bytes than remain in the buffer --
+        // decode_alpn_wire_format must stop rather than read out of bounds.
+        let wire = vec![8u8, b'h', b't', b't', b'p'];
+        assert!(decode_alpn_wire_format(wire.as_slice()).is_empty());
+    }
+
+    #[test]
+    fn select_alpn_protocol_picks_first_overlap_in_our_order() {
+        let ours = vec!["h2".to_string(), "http/1.1".to_string()];
+        let client_wire = alpn_wire_format(&["http/1.1".to_string(), "h2".to_string()]);
+        assert_eq!(select_alpn_protocol(ours.as_slice(), client_wire.as_slice()),
+                   Some("h2".as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn select_alpn_protocol_rejects_when_nothing_overlaps() {
+        let ours = vec!["h2".to_string()];
+        let client_wire = alpn_wire_format(&["http/1.1".to_string()]);
+        assert_eq!(select_alpn_protocol(ours.as_slice(), client_wire.as_slice()), None);
+    }
+}
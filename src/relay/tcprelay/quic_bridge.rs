@@ -0,0 +1,116 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Bridges the `tokio_core`/`futures`-based `relay::transport::QuicTransport`
+//! into `TcpRelayServer::run`'s blocking, thread-per-connection accept loop.
+//!
+//! `QuicTransport::listen` only makes progress while something polls the
+//! `tokio_core` reactor it was handed, so that reactor is parked on its own
+//! dedicated thread here for as long as the QUIC endpoint is bound; each
+//! accepted `QuicStream` is forwarded across an `mpsc` channel to whoever
+//! called `spawn`, which just calls `.recv()` on it exactly like
+//! `TcpAcceptor::accept()` for the plain/TLS transports.
+
+#[phase(plugin, link)]
+extern crate log;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use futures::Stream;
+use tokio_core::reactor::Core;
+
+use config::QuicConfig;
+use relay::transport::{QuicTransport, QuicStream};
+
+/// A closable handle onto the bound QUIC endpoint, alongside the
+/// `Receiver<QuicStream>` `spawn` also returns. `TcpRelayServer::run_quic`
+/// blocks on that receiver with no timeout, so -- unlike the plain/TLS
+/// listener, which `ReloadHandle::apply` unblocks by calling `TcpAcceptor::
+/// close_accept()` -- a reload changing the listen address would
+/// otherwise have no way to interrupt an in-progress `rx.recv()`.
+/// `ReloadHandle` keeps one of these around for exactly that: `close()`
+/// tells the endpoint to stop accepting, which ends the `incoming` stream
+/// `spawn`'s reactor thread is driving, which drops `tx` and makes the
+/// blocked `recv()` return `Err` immediately.
+pub struct QuicBridgeHandle {
+    endpoint: quinn::Endpoint,
+}
+
+impl QuicBridgeHandle {
+    pub fn close(&self) {
+        self.endpoint.close(0u32.into(), b"shadowsocks: reload");
+    }
+}
+
+/// Binds a QUIC endpoint for `cfg` on `addr` and returns a `Receiver` that
+/// yields one `QuicStream` per accepted bidirectional stream, plus a
+/// `QuicBridgeHandle` that can force that receiver to disconnect. The
+/// endpoint (and the thread driving it) lives until the receiver is
+/// dropped, `QuicBridgeHandle::close` is called, or the bind itself fails.
+pub fn spawn(cfg: QuicConfig, addr: SocketAddr) -> io::Result<(Receiver<QuicStream>, QuicBridgeHandle)> {
+    let (tx, rx) = channel();
+    let (endpoint_tx, endpoint_rx) = channel();
+
+    thread::spawn(move || {
+        let mut core = match Core::new() {
+            Ok(core) => core,
+            Err(e) => { error!("unable to create quic reactor: {}", e); return },
+        };
+        let handle = core.handle();
+        let transport = QuicTransport::new(&cfg);
+
+        let (endpoint, incoming) = match transport.listen(&addr, &handle) {
+            Ok(parts) => parts,
+            Err(e) => {
+                error!("unable to bind quic endpoint on {}: {}", addr, e);
+                endpoint_tx.send(Err(e)).ok();
+                return
+            },
+        };
+        if endpoint_tx.send(Ok(endpoint)).is_err() {
+            return
+        }
+
+        let forward = incoming.for_each(|stream| {
+            if tx.send(stream).is_err() {
+                // Nothing is accepting QUIC streams anymore (the accept
+                // loop in `TcpRelayServer::run_quic` rebound or shut down);
+                // stop the endpoint instead of piling up connections
+                // nobody will ever read from.
+                return Err(io::Error::new(io::ErrorKind::Other, "quic bridge receiver dropped"));
+            }
+            Ok(())
+        });
+
+        if let Err(e) = core.run(forward) {
+            error!("quic endpoint on {} stopped: {}", addr, e);
+        }
+    });
+
+    match endpoint_rx.recv() {
+        Ok(Ok(endpoint)) => Ok((rx, QuicBridgeHandle { endpoint: endpoint })),
+        Ok(Err(e)) => Err(e),
+        Err(..) => Err(io::Error::new(io::ErrorKind::Other, "quic reactor thread exited before binding")),
+    }
+}
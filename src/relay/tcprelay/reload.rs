@@ -0,0 +1,75 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Background config-file watcher that feeds `TcpRelayServer::reload_handle`
+//! hot reloads, without dropping any connection already handed off to its
+//! own copy loop.
+//!
+//! Polling the file's mtime is what's wired up here; a SIGHUP-triggered
+//! reload is the other natural trigger for this, but needs a signal
+//! handling crate this tree doesn't otherwise depend on, so it's left for
+//! whoever adds one.
+
+#[phase(plugin, link)]
+extern crate log;
+
+use std::io::timer::sleep;
+use std::io::fs::stat;
+use std::time::duration::Duration;
+
+use config::Config;
+use relay::tcprelay::server::ReloadHandle;
+
+/// Spawns a background thread that re-parses `path` (via `parse`) and
+/// pushes the result into `handle` whenever the file's mtime changes.
+/// Parse errors are logged and the previously active config is left
+/// running untouched.
+pub fn watch<F>(path: String, handle: ReloadHandle, parse: F) where F: Fn(&str) -> Result<Config, String> + Send {
+    spawn(proc() {
+        let mut last_modified = stat(&Path::new(path.clone())).ok().map(|s| s.modified);
+
+        loop {
+            sleep(Duration::seconds(1));
+
+            let modified = match stat(&Path::new(path.clone())) {
+                Ok(s) => s.modified,
+                Err(err) => {
+                    error!("Unable to stat config file {}: {}", path, err);
+                    continue
+                }
+            };
+            if Some(modified) == last_modified {
+                continue
+            }
+            last_modified = Some(modified);
+
+            match parse(path.as_slice()) {
+                Ok(new_config) => {
+                    info!("Config file {} changed, reloading", path);
+                    handle.apply(new_config);
+                },
+                Err(err) => {
+                    error!("Failed to reload config from {}, keeping previous config: {}", path, err);
+                }
+            }
+        }
+    });
+}
@@ -25,13 +25,20 @@
 extern crate log;
 
 use std::sync::{Arc, Mutex};
-use std::io::{Listener, TcpListener, Acceptor, TcpStream};
+use std::net::SocketAddr;
+use std::io::{Listener, TcpListener, Acceptor, TcpAcceptor, TcpStream};
 use std::io::{EndOfFile, TimedOut, BrokenPipe};
+use std::io::{IoResult, IoError, OtherIoError};
 
-use config::{Config, SingleServer, MultipleServer};
+use config::{Config, ServerConfig, SingleServer, MultipleServer, OutboundConfig, PluginConfig, QuicConfig, SharedConfig};
 use relay::Relay;
 use relay::socks5::{parse_request_header, SocketAddress, DomainNameAddress};
 use relay::tcprelay::cached_dns::CachedDns;
+use relay::tcprelay::reload;
+use relay::tcprelay::aead_io::{AeadReader, AeadWriter};
+use relay::tcprelay::local_stream;
+use relay::tcprelay::local_stream::LocalStream;
+use relay::tcprelay::quic_bridge;
 
 use crypto::cipher;
 use crypto::cipher::Cipher;
@@ -39,7 +46,10 @@ use crypto::cipher::CipherVariant;
 
 #[deriving(Clone)]
 pub struct TcpRelayServer {
-    config: Config,
+    config: SharedConfig,
+    listener: Arc<Mutex<Option<TcpAcceptor>>>,
+    quic_handle: Arc<Mutex<Option<quic_bridge::QuicBridgeHandle>>>,
+    rebind_requested: Arc<Mutex<bool>>,
 }
 
 impl TcpRelayServer {
@@ -57,12 +67,45 @@ impl TcpRelayServer {
             }
         }
         TcpRelayServer {
-            config: c,
+            config: SharedConfig::new(c),
+            listener: Arc::new(Mutex::new(None)),
+            quic_handle: Arc::new(Mutex::new(None)),
+            rebind_requested: Arc::new(Mutex::new(false)),
         }
     }
 
-    fn handle_connect_remote(local_stream: &mut TcpStream, remote_stream: &mut TcpStream,
+    /// Handle the config-reload watcher uses to push a freshly re-parsed
+    /// config into a running server. Credential/cipher/outbound changes
+    /// take effect for the next accepted connection with no other
+    /// action; if the listen address or port changed, the blocked
+    /// `acceptor.accept()` in `run` is forced to return so the server
+    /// rebinds to it for the plain/TLS transports, and the blocked
+    /// `rx.recv()` in `run_quic` is forced to return the same way via
+    /// `QuicBridgeHandle::close` for the `quic` one.
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle {
+            config: self.config.clone(),
+            listener: self.listener.clone(),
+            quic_handle: self.quic_handle.clone(),
+            rebind_requested: self.rebind_requested.clone(),
+        }
+    }
+
+    fn handle_connect_remote(local_stream: &mut LocalStream, remote_stream: &mut TcpStream,
                                           cipher: &mut CipherVariant) {
+        match *cipher {
+            CipherVariant::Stream(ref mut c) => {
+                TcpRelayServer::handle_connect_remote_stream(local_stream, remote_stream, &mut **c)
+            },
+            CipherVariant::Aead(ref c) => {
+                let mut writer = AeadWriter::new(c.clone());
+                TcpRelayServer::handle_connect_remote_aead(local_stream, remote_stream, &mut writer)
+            },
+        }
+    }
+
+    fn handle_connect_remote_stream(local_stream: &mut LocalStream, remote_stream: &mut TcpStream,
+                                     cipher: &mut Cipher) {
         let mut buf = [0u8, .. 0xffff];
 
         loop {
@@ -100,8 +143,61 @@ impl TcpRelayServer {
         }
     }
 
-    fn handle_connect_local(local_stream: &mut TcpStream, remote_stream: &mut TcpStream,
+    /// Same loop as the stream-cipher version, but each read from the
+    /// remote side becomes one or more sealed AEAD chunks instead of a
+    /// raw keystream XOR.
+    fn handle_connect_remote_aead(local_stream: &mut LocalStream, remote_stream: &mut TcpStream,
+                                   writer: &mut AeadWriter) {
+        let mut buf = [0u8, .. 0xffff];
+
+        loop {
+            match remote_stream.read_at_least(1, buf) {
+                Ok(len) => {
+                    let real_buf = buf.slice_to(len);
+
+                    match writer.write_chunks(local_stream, real_buf) {
+                        Ok(..) => {},
+                        Err(err) => {
+                            match err.kind {
+                                EndOfFile | TimedOut | BrokenPipe => {},
+                                _ => {
+                                    error!("Error occurs while writing to local stream: {}", err);
+                                }
+                            }
+                            remote_stream.close_read().unwrap();
+                            break
+                        }
+                    }
+                },
+                Err(err) => {
+                    match err.kind {
+                        EndOfFile | TimedOut | BrokenPipe => {},
+                        _ => {
+                            error!("Error occurs while reading from remote stream: {}", err);
+                        }
+                    }
+                    local_stream.close_write().unwrap();
+                    break
+                }
+            }
+        }
+    }
+
+    fn handle_connect_local(local_stream: &mut LocalStream, remote_stream: &mut TcpStream,
                             cipher: &mut CipherVariant) {
+        match *cipher {
+            CipherVariant::Stream(ref mut c) => {
+                TcpRelayServer::handle_connect_local_stream(local_stream, remote_stream, &mut **c)
+            },
+            CipherVariant::Aead(ref c) => {
+                let mut reader = AeadReader::new(c.clone());
+                TcpRelayServer::handle_connect_local_aead(local_stream, remote_stream, &mut reader)
+            },
+        }
+    }
+
+    fn handle_connect_local_stream(local_stream: &mut LocalStream, remote_stream: &mut TcpStream,
+                                    cipher: &mut Cipher) {
         let mut buf = [0u8, .. 0xffff];
         loop {
             match local_stream.read(buf) {
@@ -123,102 +219,553 @@ impl TcpRelayServer {
             }
         }
     }
-}
 
-impl Relay for TcpRelayServer {
-    fn run(&self) {
-        let (server_addr, server_port, password, encrypt_method, timeout) = {
-                let s = match self.config.clone().server.unwrap() {
-                    SingleServer(ref s) => {
-                        s.clone()
-                    },
-                    MultipleServer(slist) => {
-                        slist[0].clone()
+    /// `AeadReader` buffers partial chunks internally, so this loop reads
+    /// one whole chunk at a time from the local stream rather than one
+    /// arbitrary-sized `read()` worth of bytes.
+    fn handle_connect_local_aead(local_stream: &mut LocalStream, remote_stream: &mut TcpStream,
+                                  reader: &mut AeadReader) {
+        loop {
+            match reader.read_chunk(local_stream) {
+                Ok(decrypted_msg) => {
+                    remote_stream.write(decrypted_msg.as_slice())
+                            .ok().expect("Error occurs while writing to remote stream");
+                },
+                Err(err) => {
+                    match err.kind {
+                        EndOfFile | TimedOut | BrokenPipe => {},
+                        _ => {
+                            error!("Error occurs while reading from client stream: {}", err);
+                        }
                     }
-                };
-                (s.address.to_string(), s.port, Arc::new(s.password.clone()), Arc::new(s.method.clone()), s.timeout)
+                    break
+                }
+            }
+        }
+    }
+
+    /// Dials `host:port` through a local Tor SOCKS5 proxy (`socks_addr`)
+    /// instead of connecting to it directly. `host` is sent to the proxy
+    /// as-is, whether it is a domain name or the string form of an IP --
+    /// either way Tor does the resolution, not this process.
+    ///
+    /// `socks_addr`'s host and port are taken straight off the typed
+    /// `SocketAddr` (same as the `OutboundConfig::Direct` branch in
+    /// `handle_connection` below),
+    /// not by stringifying it and re-splitting on the last `:` -- that
+    /// round trip breaks for an IPv6 `socks_addr`, whose `to_string()`
+    /// brackets the address (`"[::1]:9050"`), which isn't a host
+    /// `TcpStream::connect` can resolve.
+    fn connect_via_tor(socks_addr: &SocketAddr, host: &str, port: u16) -> IoResult<TcpStream> {
+        let mut stream = try!(TcpStream::connect(socks_addr.ip().to_string().as_slice(), socks_addr.port()));
+
+        // No-auth greeting.
+        try!(stream.write([0x05, 0x01, 0x00]));
+        let mut greeting_reply = [0u8, .. 2];
+        try!(stream.read_at_least(2, greeting_reply));
+        if !socks5_greeting_accepted(greeting_reply) {
+            return Err(IoError { kind: OtherIoError,
+                                  desc: "tor socks5 proxy rejected the no-auth greeting",
+                                  detail: None });
+        }
+
+        // CONNECT request, target encoded as a domain name so Tor resolves it.
+        let request = socks5_connect_request(host, port);
+        try!(stream.write(request.as_slice()));
+
+        let mut reply_header = [0u8, .. 4];
+        try!(stream.read_at_least(4, reply_header));
+        if reply_header[1] != 0x00 {
+            return Err(IoError { kind: OtherIoError,
+                                  desc: "tor socks5 proxy refused the CONNECT request",
+                                  detail: None });
+        }
+
+        // Skip the bound address the proxy echoes back before the stream
+        // is ready to use: 4 bytes for IPv4, 16 for IPv6, or a
+        // length-prefixed name.
+        let skip = match try!(bound_addr_fixed_len(reply_header[3])) {
+            Some(len) => len,
+            None => {
+                let mut len_byte = [0u8, .. 1];
+                try!(stream.read_at_least(1, len_byte));
+                len_byte[0] as uint
+            },
+        };
+        let mut bound_addr = Vec::from_elem(skip + 2, 0u8);
+        try!(stream.read_at_least(bound_addr.len(), bound_addr.as_mut_slice()));
+
+        Ok(stream)
+    }
+
+    /// Everything from the cipher handshake through to spawning the two
+    /// copy-loop threads, shared by every transport's accept path --
+    /// `run`'s `TcpListener` loop calls this for `Plain`/`Tls` streams,
+    /// `run_quic` calls it for `Quic` ones, and neither needs to repeat
+    /// any of this per transport.
+    fn handle_connection(mut stream: LocalStream, s: ServerConfig, dnscache: Arc<CachedDns>) {
+        let password = s.password;
+        let encrypt_method = s.method;
+        let outbound = s.outbound;
+        let tor_socks_addr = s.tor.as_ref().map(|t| t.socks_addr);
+
+        let cipher_type = cipher::type_from_name(encrypt_method.as_slice())
+                                .expect("Unsupported cipher");
+
+        // AEAD connections derive *two* independent subkeys, one per
+        // direction, each from its own random salt sent in the clear --
+        // reusing one subkey/nonce sequence for both directions would
+        // mean sealing two different streams under the same key/nonce
+        // pairs, which breaks both ChaCha20-Poly1305 and AES-GCM
+        // outright. The request header is read as the first sealed
+        // chunk on the read-side cipher.
+        let (header, mut read_cipher, mut write_cipher) = if cipher_type.is_aead() {
+            let mut client_salt = Vec::from_elem(cipher_type.key_size(), 0u8);
+            stream.read_at_least(client_salt.len(), client_salt.as_mut_slice())
+                  .ok().expect("Error occurs while reading salt");
+            let decipher = match cipher::with_salt(cipher_type.clone(),
+                                                    password.as_slice().as_bytes(),
+                                                    client_salt.as_slice()) {
+                CipherVariant::Aead(c) => c,
+                CipherVariant::Stream(..) => unreachable!(),
             };
 
-        let mut acceptor = match TcpListener::bind(server_addr.as_slice(), server_port).listen() {
-            Ok(acpt) => acpt,
-            Err(e) => {
-                fail!("Error occurs while listening server address: {}", e.to_string());
+            let mut reader = AeadReader::new(decipher);
+            let header = reader.read_chunk(&mut stream)
+                                .ok().expect("Error occurs while reading header");
+
+            let encipher = cipher::with_name(encrypt_method.as_slice(),
+                                              password.as_slice().as_bytes())
+                                    .expect("Unsupported cipher");
+            let encipher = match encipher {
+                CipherVariant::Aead(c) => c,
+                CipherVariant::Stream(..) => unreachable!(),
+            };
+            stream.write(encipher.salt()).ok()
+                  .expect("Error occurs while writing salt");
+
+            (header, CipherVariant::Aead(reader.into_cipher()), CipherVariant::Aead(encipher))
+        } else {
+            // Symmetric to the AEAD salt handshake above: a CFB
+            // connection has no authentication tag to catch a
+            // mismatched keystream, so the IV has to make the same trip
+            // -- read the client's IV off the wire before building the
+            // decrypt-side cipher from it, and write our own freshly
+            // generated IV before anything gets encrypted under it.
+            let iv_size = cipher_type.iv_size();
+            let mut client_iv = Vec::from_elem(iv_size, 0u8);
+            stream.read_at_least(client_iv.len(), client_iv.as_mut_slice())
+                  .ok().expect("Error occurs while reading IV");
+            let mut read_cipher = cipher::with_iv(cipher_type.clone(),
+                                                   password.as_slice().as_bytes(),
+                                                   client_iv.as_slice());
+
+            let header = {
+                let mut buf = [0u8, .. 1024];
+                let header_len = stream.read(buf).ok()
+                                        .expect("Error occurs while reading header");
+                let encrypted_header = buf.slice_to(header_len);
+                match read_cipher {
+                    CipherVariant::Stream(ref mut c) => c.decrypt(encrypted_header),
+                    CipherVariant::Aead(..) => unreachable!(),
+                }
+            };
+
+            // `read_cipher.clone()` would have to duplicate the live CFB
+            // keystream position, which `AesCfb::box_clone` refuses to do
+            // (see its doc comment) -- so the write-direction cipher is
+            // its own independent instance, with its own random IV,
+            // rather than a clone of the read-direction one.
+            let write_cipher = cipher::with_name(encrypt_method.as_slice(),
+                                                  password.as_slice().as_bytes())
+                                    .expect("Unsupported cipher");
+            match write_cipher {
+                CipherVariant::Stream(ref c) => {
+                    stream.write(c.iv()).ok().expect("Error occurs while writing IV");
+                },
+                CipherVariant::Aead(..) => unreachable!(),
+            }
+
+            (header, read_cipher, write_cipher)
+        };
+
+        let (_, addr) = match parse_request_header(header.as_slice()) {
+            Ok((header_len, addr)) => (header_len, addr),
+            Err(..) => {
+                fail!("Error occurs while parsing request header, \
+                            maybe wrong crypto method or password");
+            }
+        };
+        info!("Connecting to {}", addr);
+        // `OutboundConfig::Tor` skips `CachedDns` for domain targets on
+        // purpose -- the hostname goes to Tor unresolved so the lookup
+        // (and the egress IP) are both inside the Tor circuit, not on
+        // this host.
+        let mut remote_stream = match outbound {
+            OutboundConfig::Direct => match addr {
+                SocketAddress(sockaddr) => {
+                    match TcpStream::connect(sockaddr.ip.to_string().as_slice(), sockaddr.port) {
+                        Ok(s) => s,
+                        Err(err) => {
+                            fail!("Unable to connect {}: {}", sockaddr, err)
+                        }
+                    }
+                },
+                DomainNameAddress(ref domainaddr) => {
+                    let candidates = match dnscache.resolve(domainaddr.domain_name.as_slice()) {
+                        Some(addrs) => addrs,
+                        None => {
+                            fail!("Failed to resolve host {}", domainaddr)
+                        }
+                    };
+
+                    // Try every cached address in order, failing over
+                    // past ones that refuse or time out instead of
+                    // giving up on the first one.
+                    let mut connected = None;
+                    for ipaddr in candidates.iter() {
+                        match TcpStream::connect(ipaddr.as_slice(), domainaddr.port) {
+                            Ok(s) => { connected = Some(s); break },
+                            Err(..) => continue,
+                        }
+                    }
+                    match connected {
+                        Some(s) => s,
+                        None => fail!("Unable to connect host {}", domainaddr),
+                    }
+                }
+            },
+            OutboundConfig::Tor => {
+                let socks_addr = tor_socks_addr.as_ref()
+                    .expect("outbound = \"tor\" requires tor.socks_addr to be configured");
+                let (host, port) = match addr {
+                    SocketAddress(ref sockaddr) => (sockaddr.ip.to_string(), sockaddr.port),
+                    DomainNameAddress(ref domainaddr) => {
+                        (domainaddr.domain_name.clone(), domainaddr.port)
+                    }
+                };
+                match TcpRelayServer::connect_via_tor(socks_addr, host.as_slice(), port) {
+                    Ok(s) => s,
+                    Err(err) => {
+                        fail!("Unable to connect {}:{} through tor: {}", host, port, err)
+                    }
+                }
             }
         };
 
-        info!("Shadowsocks listening on {}:{}", server_addr, server_port);
+        let mut remote_local_stream = stream.duplicate();
+        let mut remote_remote_stream = remote_stream.clone();
+        spawn(proc()
+            TcpRelayServer::handle_connect_remote(&mut remote_local_stream,
+                                                  &mut remote_remote_stream,
+                                                  &mut write_cipher));
+        spawn(proc()
+            TcpRelayServer::handle_connect_local(&mut stream,
+                                                 &mut remote_stream,
+                                                 &mut read_cipher));
+    }
 
-        let dnscache_arc = Arc::new(Mutex::new(CachedDns::new()));
+    /// `run`'s counterpart to the `TcpListener` accept loop when `plugin =
+    /// Quic(..)`: `quic_bridge::spawn` parks a `tokio_core` reactor on its
+    /// own thread to actually drive the QUIC endpoint, and this just reads
+    /// off the `Receiver` it hands back the same way the other transports
+    /// read off `acceptor.accept()`. Returns (rather than looping forever)
+    /// once that channel disconnects, so `run`'s outer loop can re-check
+    /// whether a reload changed the plugin or listen address.
+    ///
+    /// Stashes the accompanying `QuicBridgeHandle` in `self.quic_handle`
+    /// for the whole lifetime of the loop, and clears it on the way out --
+    /// that's what lets `ReloadHandle::apply` reach in and force a blocked
+    /// `rx.recv()` below to return via `QuicBridgeHandle::close` the same
+    /// way it forces a blocked `acceptor.accept()` to return for the
+    /// plain/TLS transports.
+    fn run_quic(&self, quic_cfg: &QuicConfig, addr: &SocketAddr,
+                timeout: Option<u64>, dnscache_arc: Arc<CachedDns>) {
+        let (rx, handle) = match quic_bridge::spawn(quic_cfg.clone(), *addr) {
+            Ok(parts) => parts,
+            Err(e) => {
+                error!("unable to bind quic endpoint on {}: {}", addr, e);
+                return
+            }
+        };
+        *self.quic_handle.lock().unwrap() = Some(handle);
 
         loop {
-            match acceptor.accept() {
-                Ok(mut stream) => {
+            if *self.rebind_requested.lock().unwrap() {
+                break
+            }
+            match rx.recv() {
+                Ok(quic_stream) => {
+                    let (send, recv) = quic_stream.split();
+                    let mut stream = LocalStream::Quic(Arc::new(Mutex::new(send)), Arc::new(Mutex::new(recv)));
                     stream.set_timeout(timeout);
-
-                    let password = password.clone();
-                    let encrypt_method = encrypt_method.clone();
+                    let s = server_config_of(&self.config.get());
                     let dnscache = dnscache_arc.clone();
+                    spawn(proc() TcpRelayServer::handle_connection(stream, s, dnscache));
+                },
+                Err(..) => {
+                    error!("quic bridge stopped producing streams, rebinding");
+                    break
+                }
+            }
+        }
 
-                    spawn(proc() {
-                        let mut cipher = cipher::with_name(encrypt_method.as_slice(),
-                                                       password.as_slice().as_bytes())
-                                                .expect("Unsupported cipher");
-
-                        let header = {
-                            let mut buf = [0u8, .. 1024];
-                            let header_len = stream.read(buf).ok()
-                                                    .expect("Error occurs while reading header");
-                            let encrypted_header = buf.slice_to(header_len);
-                            cipher.decrypt(encrypted_header)
-                        };
+        *self.quic_handle.lock().unwrap() = None;
+    }
+}
 
-                        let (_, addr) = match parse_request_header(header.as_slice()) {
-                            Ok((header_len, addr)) => (header_len, addr),
-                            Err(..) => {
-                                fail!("Error occurs while parsing request header, \
-                                            maybe wrong crypto method or password");
-                            }
-                        };
-                        info!("Connecting to {}", addr);
-                        let mut remote_stream = match addr {
-                            SocketAddress(sockaddr) => {
-                                match TcpStream::connect(sockaddr.ip.to_string().as_slice(), sockaddr.port) {
-                                    Ok(s) => s,
-                                    Err(err) => {
-                                        fail!("Unable to connect {}: {}", sockaddr, err)
-                                    }
+/// See `TcpRelayServer::reload_handle`.
+#[deriving(Clone)]
+pub struct ReloadHandle {
+    config: SharedConfig,
+    listener: Arc<Mutex<Option<TcpAcceptor>>>,
+    quic_handle: Arc<Mutex<Option<quic_bridge::QuicBridgeHandle>>>,
+    rebind_requested: Arc<Mutex<bool>>,
+}
+
+impl ReloadHandle {
+    /// Swaps in `new_config`. If its listen address/port differs from
+    /// the config currently active, also unblocks whichever transport is
+    /// currently running so it rebinds on its next iteration: closes the
+    /// listener's accept loop for the plain/TLS transports, or the QUIC
+    /// endpoint's `rx.recv()` for the `quic` one -- exactly one of
+    /// `self.listener`/`self.quic_handle` is ever populated at a time,
+    /// matching whichever of `run`/`run_quic` is currently looping.
+    pub fn apply(&self, new_config: Config) {
+        let addr_changed = {
+            let old = server_config_of(&self.config.get());
+            let new = server_config_of(&new_config);
+            listen_addr_changed(&old, &new)
+        };
+
+        self.config.swap(new_config);
+
+        if addr_changed {
+            *self.rebind_requested.lock().unwrap() = true;
+            match *self.quic_handle.lock().unwrap() {
+                Some(ref handle) => handle.close(),
+                None => {},
+            }
+            match *self.listener.lock().unwrap() {
+                Some(ref mut acceptor) => { acceptor.close_accept().unwrap(); },
+                None => {},
+            }
+        }
+    }
+}
+
+fn server_config_of(c: &Config) -> ServerConfig {
+    match c.server.clone().unwrap() {
+        SingleServer(s) => s,
+        MultipleServer(slist) => slist[0].clone(),
+    }
+}
+
+/// Whether `run`'s bound listener needs to rebind to pick up `new` --
+/// true when the address or port a fresh accept loop would bind to
+/// differs from what `old` bound, or when `plugin` changed (e.g.
+/// `Plain`->`Tls`, `Tls`->`Quic`, or new cert paths at the same plugin)
+/// -- any of these means the already-running accept loop is serving the
+/// wrong transport and has to be torn down and rebuilt, not just left
+/// alone because the socket address happens to still match.
+fn listen_addr_changed(old: &ServerConfig, new: &ServerConfig) -> bool {
+    old.address != new.address || old.port != new.port || old.plugin != new.plugin
+}
+
+/// Whether a Tor SOCKS5 proxy's reply to the no-auth greeting accepts it:
+/// version 5, no-auth method selected.
+fn socks5_greeting_accepted(reply: [u8, .. 2]) -> bool {
+    reply[0] == 0x05 && reply[1] == 0x00
+}
+
+/// Builds a SOCKS5 CONNECT request for `host:port`, with `host` always
+/// encoded as a domain name (address type `0x03`) -- Tor resolves it on
+/// the other end regardless of whether it's actually a hostname or the
+/// string form of an IP.
+fn socks5_connect_request(host: &str, port: u16) -> Vec<u8> {
+    let mut request = vec![0x05u8, 0x01, 0x00, 0x03, host.len() as u8];
+    request.push_all(host.as_bytes());
+    request.push((port >> 8) as u8);
+    request.push((port & 0xff) as u8);
+    request
+}
+
+/// The fixed length of the bound address a SOCKS5 CONNECT reply echoes
+/// back, given its address type byte -- `Some(4)` for IPv4, `Some(16)`
+/// for IPv6, or `None` for a length-prefixed name whose length still has
+/// to be read off the wire. Any other address type is a malformed reply.
+fn bound_addr_fixed_len(atyp: u8) -> IoResult<Option<uint>> {
+    match atyp {
+        0x01 => Ok(Some(4)),
+        0x04 => Ok(Some(16)),
+        0x03 => Ok(None),
+        _ => Err(IoError { kind: OtherIoError,
+                            desc: "tor socks5 proxy returned an unknown address type",
+                            detail: None }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{listen_addr_changed, socks5_greeting_accepted, socks5_connect_request, bound_addr_fixed_len};
+    use config::{ServerConfig, PluginConfig, TlsConfig};
+
+    #[test]
+    fn listen_addr_changed_detects_port_change() {
+        let old = ServerConfig::basic("127.0.0.1:8388".parse().unwrap(), "pw".to_string(), "aes-256-cfb".to_string());
+        let mut new = old.clone();
+        new.port = 9999;
+        assert!(listen_addr_changed(&old, &new));
+    }
+
+    #[test]
+    fn listen_addr_changed_ignores_password_change() {
+        let old = ServerConfig::basic("127.0.0.1:8388".parse().unwrap(), "pw".to_string(), "aes-256-cfb".to_string());
+        let mut new = old.clone();
+        new.password = "different".to_string();
+        assert!(!listen_addr_changed(&old, &new));
+    }
+
+    #[test]
+    fn listen_addr_changed_detects_plugin_change_at_the_same_address() {
+        let old = ServerConfig::basic("127.0.0.1:8388".parse().unwrap(), "pw".to_string(), "aes-256-cfb".to_string());
+        let mut new = old.clone();
+        new.plugin = PluginConfig::Tls(TlsConfig::new());
+        assert!(listen_addr_changed(&old, &new));
+    }
+
+    #[test]
+    fn listen_addr_changed_detects_plugin_field_change_within_the_same_variant() {
+        let mut old = ServerConfig::basic("127.0.0.1:8388".parse().unwrap(), "pw".to_string(), "aes-256-cfb".to_string());
+        old.plugin = PluginConfig::Tls(TlsConfig::new());
+        let mut new = old.clone();
+        if let PluginConfig::Tls(ref mut cfg) = new.plugin {
+            cfg.cert_path = Some("/etc/new-cert.pem".to_string());
+        }
+        assert!(listen_addr_changed(&old, &new));
+    }
+
+    #[test]
+    fn socks5_greeting_accepted_requires_version_5_and_no_auth() {
+        assert!(socks5_greeting_accepted([0x05, 0x00]));
+        assert!(!socks5_greeting_accepted([0x05, 0x02]));
+        assert!(!socks5_greeting_accepted([0x04, 0x00]));
+    }
+
+    #[test]
+    fn socks5_connect_request_encodes_domain_name_and_big_endian_port() {
+        let request = socks5_connect_request("example.com", 443);
+        assert_eq!(request.as_slice(),
+                   [0x05, 0x01, 0x00, 0x03, 0x0b,
+                    b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c', b'o', b'm',
+                    0x01, 0xbb].as_slice());
+    }
+
+    #[test]
+    fn bound_addr_fixed_len_covers_ipv4_ipv6_and_domain_name() {
+        assert_eq!(bound_addr_fixed_len(0x01).unwrap(), Some(4));
+        assert_eq!(bound_addr_fixed_len(0x04).unwrap(), Some(16));
+        assert_eq!(bound_addr_fixed_len(0x03).unwrap(), None);
+    }
+
+    #[test]
+    fn bound_addr_fixed_len_rejects_unknown_address_type() {
+        assert!(bound_addr_fixed_len(0x02).is_err());
+    }
+}
+
+impl Relay for TcpRelayServer {
+    fn run(&self) {
+        let dnscache_arc = Arc::new(CachedDns::new(&self.config.get().dns));
+
+        // Hand the watcher our own reload handle so a config file change
+        // reaches the exact same `apply` path a manually-constructed
+        // `ReloadHandle` would -- this is the only thing that ever
+        // produces a new `Config` for a running server to pick up.
+        if let Some(path) = self.config.get().config_path.clone() {
+            let config_for_reload = self.config.clone();
+            reload::watch(path, self.reload_handle(), move |p| {
+                let previous = server_config_of(&config_for_reload.get());
+                Config::reload_from_file(p, &previous)
+            });
+        }
+
+        // Outer loop rebinds (or switches transport) when a reload
+        // changes the server address/port/plugin; the inner loop accepts
+        // connections under whatever's currently bound.
+        loop {
+            let (server_addr, server_port, timeout, plugin) = {
+                let s = server_config_of(&self.config.get());
+                (s.address.to_string(), s.port, s.timeout, s.plugin.clone())
+            };
+
+            if let PluginConfig::Quic(ref quic_cfg) = plugin {
+                let bind_addr = SocketAddr::new(server_config_of(&self.config.get()).address.ip(), server_port);
+                info!("Shadowsocks (quic) listening on {}", bind_addr);
+                *self.listener.lock().unwrap() = None;
+                *self.rebind_requested.lock().unwrap() = false;
+                self.run_quic(quic_cfg, &bind_addr, timeout, dnscache_arc.clone());
+                continue
+            }
+
+            let ssl_ctx = match plugin {
+                PluginConfig::Tls(ref tls_cfg) => Some(local_stream::build_ssl_context(tls_cfg)),
+                _ => None,
+            };
+
+            let mut acceptor = match TcpListener::bind(server_addr.as_slice(), server_port).listen() {
+                Ok(acpt) => acpt,
+                Err(e) => {
+                    fail!("Error occurs while listening server address: {}", e.to_string());
+                }
+            };
+
+            info!("Shadowsocks listening on {}:{}", server_addr, server_port);
+
+            *self.listener.lock().unwrap() = Some(acceptor.clone());
+            *self.rebind_requested.lock().unwrap() = false;
+
+            'accept: loop {
+                match acceptor.accept() {
+                    Ok(mut raw_stream) => {
+                        raw_stream.set_timeout(timeout);
+
+                        let mut stream = match ssl_ctx {
+                            Some(ref ctx) => match local_stream::accept_tls(raw_stream, ctx) {
+                                Ok(s) => s,
+                                Err(err) => {
+                                    error!("TLS handshake with client failed: {}", err);
+                                    continue
                                 }
                             },
-                            DomainNameAddress(ref domainaddr) => {
-                                let ipaddr = match dnscache.lock().resolve(domainaddr.domain_name.as_slice()) {
-                                    Some(addr) => addr,
-                                    None => {
-                                        fail!("Failed to resolve host {}", domainaddr)
-                                    }
-                                };
-
-                                TcpStream::connect(ipaddr.to_string().as_slice(), domainaddr.port)
-                                    .ok().expect(format!("Unable to connect host {}", domainaddr).as_slice())
-                            }
+                            None => LocalStream::Plain(raw_stream),
                         };
+                        // `TlsShared::new` repins the socket to its short
+                        // poll interval, so the idle timeout has to be set
+                        // again on the wrapped stream.
+                        stream.set_timeout(timeout);
 
-                        let mut remote_local_stream = stream.clone();
-                        let mut remote_remote_stream = remote_stream.clone();
-                        let mut remote_cipher = cipher.clone();
-                        spawn(proc()
-                            TcpRelayServer::handle_connect_remote(&mut remote_local_stream,
-                                                                  &mut remote_remote_stream,
-                                                                  &mut remote_cipher));
-                        spawn(proc()
-                            TcpRelayServer::handle_connect_local(&mut stream,
-                                                                 &mut remote_stream,
-                                                                 &mut cipher));
-                    });
-                },
-                Err(e) => {
-                    fail!("Error occurs while accepting: {}", e.to_string());
+                        // Re-read the shared config for every accepted
+                        // connection rather than once per listener bind, so
+                        // a reload's new password/cipher/outbound settings
+                        // take effect immediately for newly accepted
+                        // connections -- the copy loops spawned below keep
+                        // whatever cipher/outbound they captured here for
+                        // their whole lifetime.
+                        let s = server_config_of(&self.config.get());
+                        let dnscache = dnscache_arc.clone();
+
+                        spawn(proc() TcpRelayServer::handle_connection(stream, s, dnscache));
+                    },
+                    Err(..) if *self.rebind_requested.lock().unwrap() => {
+                        info!("Config reload changed the listen address, rebinding");
+                        break 'accept
+                    },
+                    Err(e) => {
+                        fail!("Error occurs while accepting: {}", e.to_string());
+                    }
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,93 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Tor integration: publishing the server as a v3 onion service over the
+//! control port.
+//!
+//! This module only talks to the *local* `tor` daemon -- it never touches
+//! the network directly. `publish_onion_service` runs once at startup
+//! from `relay::server::run`.
+//!
+//! The outbound side (dialing a target through Tor's SOCKS5 port when
+//! `ServerConfig::outbound` is `OutboundConfig::Tor`) lives in
+//! `TcpRelayServer::connect_via_tor` instead of here: that connect path
+//! is the blocking, thread-per-connection one `TcpRelayServer::run`
+//! actually runs, so the handshake is written against the same blocking
+//! `std::io` API rather than against `tokio_core`/`futures`: no reactor
+//! runs on those connection threads (the only one `TcpRelayServer`
+//! starts is `relay::tcprelay::quic_bridge`'s, for QUIC).
+
+use std::io;
+
+use torut::control::{AuthenticatedConn, UnauthenticatedConn, TorAuthData, TorAuthMethod};
+use torut::onion::TorSecretKeyV3;
+
+use config::{TorConfig, TorControlAuth};
+
+/// Connects to the control port, authenticates, generates a fresh
+/// ephemeral v3 onion key and maps `onion_port` on the resulting
+/// `.onion` address to `local_port` on `127.0.0.1`. Returns the
+/// `.onion` hostname (without the port) so the caller can log it.
+///
+/// The onion service lives only as long as the control connection stays
+/// open -- matching the ephemeral, no-state-on-disk model the shadowsocks
+/// server otherwise has.
+pub fn publish_onion_service(cfg: &TorConfig, local_port: u16) -> io::Result<String> {
+    let control_stream = ::std::net::TcpStream::connect(cfg.control_addr)?;
+    let mut unauth_conn = UnauthenticatedConn::new(control_stream);
+
+    let proto_info = unauth_conn.load_protocol_info()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("tor PROTOCOLINFO failed: {:?}", e)))?;
+    let auth_data = match cfg.control_auth {
+        TorControlAuth::Null => TorAuthData::Null,
+        TorControlAuth::CookieFile(ref path) => {
+            TorAuthData::Cookie(::std::fs::read(path)?.into())
+        },
+        TorControlAuth::Password(ref password) => TorAuthData::HashedPassword(password.clone().into()),
+    };
+    if !proto_info.auth_methods.contains(&method_for(&auth_data)) {
+        return Err(io::Error::new(io::ErrorKind::Other, "tor control port doesn't support the configured auth method"));
+    }
+
+    let mut conn: AuthenticatedConn<_, ()> = unauth_conn.into_authenticated(&auth_data)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("tor AUTHENTICATE failed: {:?}", e)))?;
+
+    let key = TorSecretKeyV3::generate();
+    let onion_addr = key.public().get_onion_address();
+
+    conn.add_onion_v3(&key, false, false, false, None,
+                       &mut vec![(cfg.onion_port, format!("127.0.0.1:{}", local_port).parse().unwrap())].into_iter())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("tor ADD_ONION failed: {:?}", e)))?;
+
+    // The service is not detached, so Tor removes it when this control
+    // connection closes; keep the connection open for the process's life.
+    ::std::mem::forget(conn);
+
+    Ok(onion_addr.to_string())
+}
+
+fn method_for(auth: &TorAuthData) -> TorAuthMethod {
+    match *auth {
+        TorAuthData::Null => TorAuthMethod::Null,
+        TorAuthData::Cookie(..) => TorAuthMethod::Cookie,
+        TorAuthData::HashedPassword(..) => TorAuthMethod::HashedPassword,
+    }
+}